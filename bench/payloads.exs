@@ -0,0 +1,31 @@
+# Times round trips of large payloads through JavaScript. Run with
+#
+#     MIX_ENV=prod mix run bench/payloads.exs
+#
+# and compare against a checkout from before values were converted directly
+# (the parent of the commit that stopped going through JSON).
+
+{:ok, env} = JSEngine.create_env()
+{:ok, nil} = JSEngine.run(env, "function echo(x) { return x; }")
+
+runs = 20
+
+payloads = [
+  {"10k maps", for(i <- 1..10_000, do: %{"id" => i, "name" => "item #{i}", "score" => i + 0.5})},
+  {"100k integers", Enum.to_list(1..100_000)},
+  {"1k nested lists", for(i <- 1..1_000, do: Enum.to_list(i..(i + 50)))},
+  {"1 MB string", String.duplicate("x", 1_000_000)}
+]
+
+for {name, payload} <- payloads do
+  {:ok, ^payload} = JSEngine.call(env, "echo", [payload])
+
+  times =
+    for _ <- 1..runs do
+      {micros, {:ok, _}} = :timer.tc(fn -> JSEngine.call(env, "echo", [payload]) end)
+      micros
+    end
+
+  median = times |> Enum.sort() |> Enum.at(div(runs, 2))
+  IO.puts("#{String.pad_trailing(name, 16)} median #{Float.round(median / 1000, 2)} ms")
+end
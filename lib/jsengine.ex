@@ -1,26 +1,445 @@
 defmodule JSEngine do
+  # Test builds can make environments fail on purpose with `inject_fault/2`
   use Rustler,
     otp_app: :jsengine,
-    crate: :jsengine
+    crate: :jsengine,
+    features: if(Mix.env() == :test, do: ["fault-injection"], else: [])
 
   # NIFs - these are replaced by Rust implementations
-  def create_env(), do: error()
+  def create_env_with_options(_opts), do: error()
   def destroy_env(_env_id), do: error()
-  def load_env(_env_id, _files), do: error()
-  def run_env(_env_id, _code), do: error()
-  def call_env(_env_id, _function_name, _args), do: error()
+  def reset_env(_env_id), do: error()
+  def load_env(_env_id, _files, _opts), do: error()
+  def run_env(_env_id, _code, _bindings, _opts), do: error()
+  def call_env(_env_id, _function_name, _args, _opts), do: error()
+  def call_many_env(_env_id, _calls, _opts), do: error()
+  def construct_env(_env_id, _class_name, _args, _opts), do: error()
+  def get_global_env(_env_id, _name, _opts), do: error()
+  def set_global_env(_env_id, _name, _value), do: error()
+  def call_export_env(_env_id, _specifier, _export_name, _args, _opts), do: error()
+  def call_handle_env(_fun, _args, _opts), do: error()
+  def get_handle_property(_handle, _key, _opts), do: error()
+  def set_handle_property(_handle, _key, _value), do: error()
+  def invoke_handle_method(_handle, _method, _args, _opts), do: error()
+  def release_handle(_handle), do: error()
+  def await_promise_env(_promise, _opts), do: error()
+  def promise_state_env(_promise), do: error()
+  def stream_call_env(_env_id, _function_name, _args, _to, _demand, _opts), do: error()
+  def stream_ack_env(_stream, _demand), do: error()
+  def stream_close_env(_stream), do: error()
+  def load_env_async(_env_id, _files, _opts), do: error()
+  def run_env_async(_env_id, _code, _bindings, _opts), do: error()
+  def call_env_async(_env_id, _function_name, _args, _opts), do: error()
+  # Stops an async request, whether it is still queued or already running, and
+  # drops any timers it started. Its result is then {:error, :canceled}.
+  def cancel(_ref), do: error()
+  def create_pool_with_options(_size, _opts), do: error()
+  def checkout(_pool), do: error()
+  def checkin(_pool, _env_id), do: error()
+  # Evaluates `files` in a fresh runtime and writes a V8 startup snapshot of the
+  # result to `out_path`, returning `{:ok, nil}`. ES modules among them can be
+  # called with `call_export/4` in environments started from it. Pending timers
+  # and other in-flight work are not captured.
+  def create_snapshot(_files, _out_path), do: error()
+  def struct_resolved(_lookup, _default), do: error()
+  def inject_fault(_env_id, _fault), do: error()
+
+  # Creates an independent environment. Options:
+  #
+  #   * `:max_heap_size` - heap limit in bytes; scripts that reach it are
+  #     stopped with `{:error, :out_of_memory}` and the environment is reset
+  #   * `:initial_heap_size` - initial heap size in bytes
+  #   * `:snapshot` - path to a file written by `create_snapshot/2`; the
+  #     environment starts with those files already loaded
+  #   * `:preload` - files to load on creation and again after every
+  #     `reset_env/1`; if any fails to load, the error is returned instead
+  def create_env(opts \\ []) when is_list(opts) do
+    create_env_with_options(env_opts(opts))
+  end
+
+  # Creates a pool of `size` environments that are kept warm, each with the
+  # files in `:preload` already loaded. Accepts the same options as
+  # `create_env/1`. Use `checkout/1` to take an environment and `checkin/2` to
+  # return it; returned environments are replaced with fresh ones, so every
+  # checkout starts clean. An exhausted pool grows, and shrinks back to `size`
+  # idle environments as they are returned. A checkout whose environment failed
+  # to preload returns `{:error, reason}`.
+  def create_pool(size, opts \\ []) when is_integer(size) and size >= 0 and is_list(opts) do
+    create_pool_with_options(size, env_opts(opts))
+  end
+
+  # Clears all state in an environment, keeping the same handle, and loads its
+  # `:preload` files again. The default environment can be reset too.
+  def reset_env(), do: reset_env(:default)
+
+  # Functions can be named by a dotted path ("MyLib.utils.format") or a list of
+  # keys (["MyLib", "utils", "format"]); they are called with the object they
+  # were found on as `this`.
+  defguardp is_path(path) when is_binary(path) or is_list(path)
 
   # Convenience wrappers for default environment
-  def load(files) when is_list(files), do: load_env(:default, files)
-  def run(code) when is_binary(code), do: run_env(:default, code)
-  def call(function_name, args \\ []) when is_binary(function_name),
-    do: call_env(:default, function_name, args)
-
-  # Support both default and custom environments
-  def load(env_id, files) when is_list(files), do: load_env(env_id, files)
-  def run(env_id, code) when is_binary(code), do: run_env(env_id, code)
-  def call(env_id, function_name, args) when is_binary(function_name),
-    do: call_env(env_id, function_name, args)
+  def load(files) when is_list(files), do: load(:default, files, [])
+  def run(code) when is_binary(code), do: run(:default, code, [])
+  def call(function_name, args \\ []) when is_path(function_name),
+    do: call(:default, function_name, args, [])
+
+  # Support both default and custom environments. Options:
+  #
+  #   * `:timeout_ms` - stop the script and return `{:error, :timeout}` once it
+  #     has run this long; the environment stays usable afterwards
+  #   * `:return` - `:value` (default) to convert the result to Elixir terms, or
+  #     `:handle` to keep it in the environment and return a `%JSEngine.Handle{}`
+  #   * `:await` - `true` (default) to wait for a returned promise to settle, or
+  #     `false` to return it right away as a `%JSEngine.Promise{}`
+  #   * `:bindings` - `run/3` only: a map of values visible to the code as local
+  #     variables, e.g. `run(env, "price * qty", bindings: %{"price" => 3, "qty" => 2})`.
+  #     Names must be JavaScript identifiers; nothing is left behind in globals.
+  #   * `:bigint` - pass every integer a JS number can't hold exactly (beyond
+  #     ±2^53) as a `BigInt`, instead of only those too big for 64 bits
+  #   * `:tuples` - `:array` (default) to pass tuples as arrays, or `:tagged` to
+  #     pass them as `{__jsengine__: "tuple", items: [...]}` objects. Objects in
+  #     that form come back as tuples either way.
+  #   * `:structs` - modules whose structs results may be decoded into, any
+  #     module already loaded by default. Structs are passed as objects with a
+  #     `__struct__` field naming their module, like `"MyApp.User"`; an object
+  #     naming one of these modules comes back as that struct, with fields it
+  #     lacks set to their defaults and any others dropped. Objects naming other
+  #     modules come back as plain maps; modules are never loaded for this.
+  #
+  # Binaries that aren't valid UTF-8 are passed to JavaScript as `Uint8Array`s;
+  # wrap one as `{:binary, data}` to pass it as bytes even when it is valid
+  # text. Bytes come back wrapped the same way, so they can be told apart from
+  # strings. Keyword lists are passed as plain objects, so option lists can go
+  # straight into JavaScript APIs; lists of pairs that repeat a key, like
+  # `[{:ok, 1}, {:ok, 2}]`, are passed as arrays instead. Typed arrays,
+  # `DataView`s and `ArrayBuffer`s come back as `{:binary, data}`, `BigInt`s
+  # as integers, however large, and `Map`s as maps with keys of any type.
+  #
+  # If the engine itself crashes, the request fails with
+  # `{:error, {:panic, message}}`, or `{:error, :env_lost}` if the environment's
+  # thread is gone. Either way the environment restarts empty.
+  def load(files, opts) when is_list(files) and is_list(opts), do: load(:default, files, opts)
+  def load(env_id, files) when is_list(files), do: load(env_id, files, [])
+  def load(env_id, files, opts) when is_list(files) and is_list(opts),
+    do: load_env(env_id, files, exec_opts(opts))
+
+  def run(code, opts) when is_binary(code) and is_list(opts), do: run(:default, code, opts)
+  def run(env_id, code) when is_binary(code), do: run(env_id, code, [])
+  def run(env_id, code, opts) when is_binary(code) and is_list(opts),
+    do: run_env(env_id, code, Keyword.get(opts, :bindings, %{}), exec_opts(opts))
+
+  def call(function_name, args, opts) when is_path(function_name) and is_list(opts),
+    do: call(:default, function_name, args, opts)
+
+  def call(env_id, function_name, args) when is_path(function_name),
+    do: call(env_id, function_name, args, [])
+
+  def call(env_id, function_name, args, opts) when is_path(function_name) and is_list(opts),
+    do: call_env(env_id, function_name, args, exec_opts(opts))
+
+  # Makes several calls in one round trip, e.g. `[{"validate", [a]}, {"validate", [b]}]`,
+  # returning `{:ok, results}` with an `{:ok, _}` or `{:error, _}` per call, in
+  # order. Options apply to the whole batch, so `:timeout_ms` bounds all of it.
+  def call_many(calls) when is_list(calls), do: call_many(:default, calls, [])
+  def call_many(env_id, calls) when is_list(calls), do: call_many(env_id, calls, [])
+  def call_many(env_id, calls, opts) when is_list(calls) and is_list(opts),
+    do: call_many_env(env_id, calls, exec_opts(opts))
+
+  # Creates an instance of a class (or constructor function) as with `new`. The
+  # class is named by a path like a function, and the instance is returned
+  # converted, or as a `%JSEngine.Handle{}` with `return: :handle`.
+  def construct(class_name, args \\ []) when is_path(class_name),
+    do: construct(:default, class_name, args, [])
+
+  def construct(class_name, args, opts) when is_path(class_name) and is_list(opts),
+    do: construct(:default, class_name, args, opts)
+
+  def construct(env_id, class_name, args) when is_path(class_name),
+    do: construct(env_id, class_name, args, [])
+
+  def construct(env_id, class_name, args, opts) when is_path(class_name) and is_list(opts),
+    do: construct_env(env_id, class_name, args, exec_opts(opts))
+
+  # Reads and writes global variables directly, without building code strings.
+  # Names can be paths; for `set_global/3` every part but the last must exist.
+  # `get_global/3` accepts `:return` like `call/4`.
+  def get_global(name) when is_path(name), do: get_global(:default, name, [])
+  def get_global(env_id, name) when is_path(name), do: get_global(env_id, name, [])
+  def get_global(env_id, name, opts) when is_path(name) and is_list(opts),
+    do: get_global_env(env_id, name, exec_opts(opts))
+
+  def set_global(name, value) when is_path(name), do: set_global(:default, name, value)
+  def set_global(env_id, name, value) when is_path(name),
+    do: set_global_env(env_id, name, value)
+
+  # Calls an export of an ES module loaded with `load/2`, without it having to
+  # be copied onto the global object. The specifier is the module's URL
+  # ("file:///.../calculator.js") or its file path; the export name can be a
+  # path like a function name.
+  def call_export(specifier, export_name, args) when is_binary(specifier),
+    do: call_export(:default, specifier, export_name, args, [])
+
+  def call_export(specifier, export_name, args, opts) when is_binary(specifier) and is_list(opts),
+    do: call_export(:default, specifier, export_name, args, opts)
+
+  def call_export(env_id, specifier, export_name, args) when is_binary(specifier),
+    do: call_export(env_id, specifier, export_name, args, [])
+
+  def call_export(env_id, specifier, export_name, args, opts)
+      when is_binary(specifier) and is_path(export_name) and is_list(opts),
+      do: call_export_env(env_id, specifier, export_name, args, exec_opts(opts))
+
+  # Calls a function that an environment returned as a `%JSEngine.Function{}`,
+  # e.g. from a factory or a curried API
+  def call_handle(%JSEngine.Function{} = fun, args \\ [], opts \\ []) when is_list(opts),
+    do: call_handle_env(fun, args, exec_opts(opts))
+
+  # Work with values kept in an environment, either `%JSEngine.Handle{}` or
+  # `%JSEngine.Function{}`. Keys and method names can be paths, like function
+  # names. `get_property/3` and `invoke_method/4` take the same options as
+  # `call/4`, so their results can be handles too.
+  defguardp is_handle(handle)
+            when is_struct(handle, JSEngine.Handle) or is_struct(handle, JSEngine.Function)
+
+  def get_property(handle, key, opts \\ [])
+      when is_handle(handle) and is_path(key) and is_list(opts),
+      do: get_handle_property(handle, key, exec_opts(opts))
+
+  def set_property(handle, key, value) when is_handle(handle) and is_path(key),
+    do: set_handle_property(handle, key, value)
+
+  def invoke_method(handle, method, args \\ [], opts \\ [])
+      when is_handle(handle) and is_path(method) and is_list(opts),
+      do: invoke_handle_method(handle, method, args, exec_opts(opts))
+
+  # Frees a handle's value now rather than when the struct is garbage collected;
+  # using the handle afterwards returns an error
+  def release(handle) when is_handle(handle), do: release_handle(handle)
+
+  # Waits up to `timeout` ms for a promise returned with `await: false` and
+  # returns its result. On `{:error, :timeout}` the promise keeps going and can
+  # be awaited again. Accepts `:return` like `call/4`.
+  #
+  # A pending promise only makes progress while its environment is handling
+  # requests, so work started with timers catches up once it is next awaited.
+  def await_promise(%JSEngine.Promise{} = promise, timeout \\ 5000, opts \\ [])
+      when (is_integer(timeout) or timeout == :infinity) and is_list(opts) do
+    timeout_ms = if timeout == :infinity, do: nil, else: timeout
+    await_promise_env(promise, exec_opts([timeout_ms: timeout_ms, await: true] ++ opts))
+  end
+
+  # Returns `{:ok, :pending | :fulfilled | :rejected}` without waiting
+  def promise_state(%JSEngine.Promise{} = promise), do: promise_state_env(promise)
+
+  # Non-blocking variants: return {:ok, ref} right away and deliver the result
+  # to the calling process as {:jsengine, ref, result}
+  def load_async(files) when is_list(files), do: load_async(:default, files, [])
+  def load_async(files, opts) when is_list(files) and is_list(opts),
+    do: load_async(:default, files, opts)
+
+  def load_async(env_id, files) when is_list(files), do: load_async(env_id, files, [])
+
+  def load_async(env_id, files, opts) when is_list(files) and is_list(opts),
+    do: load_env_async(env_id, files, exec_opts(opts))
+
+  def run_async(code) when is_binary(code), do: run_async(:default, code, [])
+  def run_async(code, opts) when is_binary(code) and is_list(opts),
+    do: run_async(:default, code, opts)
+
+  def run_async(env_id, code) when is_binary(code), do: run_async(env_id, code, [])
+
+  def run_async(env_id, code, opts) when is_binary(code) and is_list(opts),
+    do: run_env_async(env_id, code, Keyword.get(opts, :bindings, %{}), exec_opts(opts))
+
+  def call_async(function_name, args) when is_path(function_name),
+    do: call_async(:default, function_name, args, [])
+
+  def call_async(function_name, args, opts) when is_path(function_name) and is_list(opts),
+    do: call_async(:default, function_name, args, opts)
+
+  def call_async(env_id, function_name, args) when is_path(function_name),
+    do: call_async(env_id, function_name, args, [])
+
+  def call_async(env_id, function_name, args, opts)
+      when is_path(function_name) and is_list(opts),
+      do: call_env_async(env_id, function_name, args, exec_opts(opts))
+
+  # Calls a function returning an iterable (an async generator, a generator, an
+  # array...) and sends what it yields to a process as it goes:
+  #
+  #   * `{:jsengine, ref, {:next, value}}` for every value
+  #   * `{:jsengine, ref, :done}` once the iterator is done
+  #   * `{:jsengine, ref, {:error, reason}}` if it fails
+  #
+  # Returns `{:ok, %JSEngine.Stream{ref: ref}}`. Options, besides those of
+  # `call/4`:
+  #
+  #   * `:to` - the process to send to, the caller by default
+  #   * `:demand` - how many values to send before waiting for `stream_ack/2`,
+  #     100 by default. The environment handles other requests while a stream
+  #     waits, but not while it sends, so `:infinity` holds the environment
+  #     until the iterator is done.
+  def stream_call(env_id, function_name, args, opts \\ [])
+      when is_path(function_name) and is_list(opts) do
+    to = Keyword.get(opts, :to, self())
+    demand = Keyword.get(opts, :demand, 100)
+    demand = if demand == :infinity, do: nil, else: demand
+    ref = make_ref()
+
+    case stream_call_env(env_id, function_name, args, {to, ref}, demand, exec_opts(opts)) do
+      {:ok, resource} -> {:ok, %JSEngine.Stream{ref: ref, resource: resource}}
+      error -> error
+    end
+  end
+
+  # Lets a stream started by `stream_call/4` send `demand` more values
+  def stream_ack(%JSEngine.Stream{resource: resource}, demand)
+      when is_integer(demand) and demand >= 0,
+      do: stream_ack_env(resource, demand)
+
+  # Stops a stream early; streams that are not read to the end should be closed
+  def stream_close(%JSEngine.Stream{resource: resource}), do: stream_close_env(resource)
+
+  # Wraps `stream_call/4` in a lazy `Stream`, asking for `:demand` values (100
+  # by default) at a time. Waits up to `:timeout` ms (5000 by default) for each
+  # value, and raises if the stream fails or times out.
+  def stream(env_id, function_name, args, opts \\ [])
+      when is_path(function_name) and is_list(opts) do
+    demand = Keyword.get(opts, :demand, 100)
+    timeout = Keyword.get(opts, :timeout, 5000)
+
+    Stream.resource(
+      fn ->
+        opts = Keyword.merge(opts, to: self(), demand: demand)
+
+        case stream_call(env_id, function_name, args, opts) do
+          {:ok, stream} -> {stream, demand}
+          {:error, reason} -> raise "JavaScript stream failed to start: #{inspect(reason)}"
+        end
+      end,
+      fn
+        {stream, :done} ->
+          {:halt, {stream, :done}}
+
+        {stream, 0} ->
+          :ok = stream_ack(stream, demand)
+          {[], {stream, demand}}
+
+        {%JSEngine.Stream{ref: ref} = stream, pending} ->
+          receive do
+            {:jsengine, ^ref, {:next, value}} -> {[value], {stream, pending - 1}}
+            {:jsengine, ^ref, :done} -> {:halt, {stream, :done}}
+            {:jsengine, ^ref, {:error, reason}} ->
+              raise "JavaScript stream failed: #{inspect(reason)}"
+          after
+            timeout -> raise "JavaScript stream timed out after #{timeout} ms"
+          end
+      end,
+      fn {stream, _} ->
+        stream_close(stream)
+        flush_stream(stream.ref)
+      end
+    )
+  end
+
+  defp flush_stream(ref) do
+    receive do
+      {:jsengine, ^ref, _} -> flush_stream(ref)
+    after
+      0 -> :ok
+    end
+  end
+
+  # Waits for the result of an async request
+  def await(ref, timeout \\ 5000) do
+    receive do
+      {:jsengine, ^ref, result} -> result
+    after
+      timeout -> {:error, :timeout}
+    end
+  end
+
+  defp env_opts(opts) do
+    %{
+      initial_heap_size: Keyword.get(opts, :initial_heap_size),
+      max_heap_size: Keyword.get(opts, :max_heap_size),
+      snapshot: Keyword.get(opts, :snapshot),
+      preload: Keyword.get(opts, :preload, [])
+    }
+  end
+
+  defp exec_opts(opts) do
+    %{
+      timeout_ms: Keyword.get(opts, :timeout_ms),
+      returns: Keyword.get(opts, :return, :value),
+      awaits: Keyword.get(opts, :await, true),
+      bigint: Keyword.get(opts, :bigint, false),
+      tuples: Keyword.get(opts, :tuples, :array),
+      structs: opts |> Keyword.get(:structs, :loaded) |> struct_defaults()
+    }
+  end
+
+  # Structs are passed to the engine as their default values, so fields a
+  # JavaScript object leaves out keep them. Modules that can't be loaded or
+  # don't define a struct are left out.
+  defp struct_defaults(:loaded), do: struct_resolver()
+
+  defp struct_defaults(modules) when is_list(modules) do
+    for module <- modules,
+        Code.ensure_loaded?(module),
+        function_exported?(module, :__struct__, 0),
+        do: module.__struct__()
+  end
+
+  # The structs of loaded modules are looked up by a process the engine asks
+  # whenever an object names a module, so results without any cost nothing.
+  # It is started on first use.
+  defp struct_resolver() do
+    case Process.whereis(JSEngine.StructResolver) do
+      nil -> start_struct_resolver()
+      pid -> pid
+    end
+  end
+
+  defp start_struct_resolver() do
+    pid = spawn(fn -> resolve_structs() end)
+
+    try do
+      Process.register(pid, JSEngine.StructResolver)
+      pid
+    rescue
+      # Another process started one first
+      ArgumentError ->
+        Process.exit(pid, :kill)
+        struct_resolver()
+    end
+  end
+
+  defp resolve_structs() do
+    receive do
+      {:lookup_struct, name, lookup} ->
+        struct_resolved(lookup, loaded_struct(name))
+        resolve_structs()
+    end
+  end
+
+  # Modules are never loaded for this, and a name that isn't an atom yet can't
+  # be a loaded module
+  defp loaded_struct(name) do
+    ["Elixir." <> name, name]
+    |> Enum.map(&existing_atom/1)
+    |> Enum.find(&function_exported?(&1, :__struct__, 0))
+    |> case do
+      nil -> nil
+      module -> module.__struct__()
+    end
+  end
+
+  defp existing_atom(name) do
+    String.to_existing_atom(name)
+  rescue
+    ArgumentError -> nil
+  end
 
   defp error(), do: :erlang.nif_error(:nif_not_loaded)
 end
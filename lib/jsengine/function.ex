@@ -0,0 +1,7 @@
+defmodule JSEngine.Function do
+  # A JavaScript function returned from an environment. It can be called with
+  # `JSEngine.call_handle/3`, and stays alive in the environment for as long as
+  # this struct is referenced.
+  @enforce_keys [:env, :handle]
+  defstruct [:env, :handle]
+end
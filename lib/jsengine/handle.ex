@@ -0,0 +1,9 @@
+defmodule JSEngine.Handle do
+  # A JavaScript value kept in its environment instead of being converted, as
+  # returned with `return: :handle`. Work with it through
+  # `JSEngine.get_property/3`, `JSEngine.set_property/3` and
+  # `JSEngine.invoke_method/4`. The value is freed by `JSEngine.release/1`, when
+  # this struct is garbage collected, or when the environment is destroyed.
+  @enforce_keys [:env, :handle]
+  defstruct [:env, :handle]
+end
@@ -0,0 +1,7 @@
+defmodule JSEngine.Promise do
+  # A promise left pending in its environment by a run or call with
+  # `await: false`. Wait for it with `JSEngine.await_promise/2`, or check on it
+  # with `JSEngine.promise_state/1`.
+  @enforce_keys [:env, :handle]
+  defstruct [:env, :handle]
+end
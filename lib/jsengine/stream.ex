@@ -0,0 +1,6 @@
+defmodule JSEngine.Stream do
+  # A stream started by `JSEngine.stream_call/4`. Its messages are tagged with
+  # `ref`; the stream is closed once this struct is no longer referenced.
+  @enforce_keys [:ref, :resource]
+  defstruct [:ref, :resource]
+end
@@ -26,7 +26,27 @@ rustler::atoms! {
     true_ = "true",
     false_ = "false",
     __struct__,
+    lookup_struct,
 
     // Environment management
-    default
+    default,
+
+    // Async replies
+    jsengine,
+    canceled,
+
+    // Data
+    binary,
+
+    // Streams
+    next,
+    done,
+
+    // Execution limits
+    timeout,
+    out_of_memory,
+
+    // Failures
+    panic,
+    env_lost
 }
@@ -1,10 +1,767 @@
 use crate::atoms;
+use crate::engine::{ExecOptions, Tuples};
 use crate::error::Error as AtomError;
-use deno_core::serde_json::{json, Value};
-use deno_core::serde_v8::Error as SerdeV8Error;
-use deno_core::{anyhow, serde_json};
-use rustler::{types::atom, Atom, Encoder, Env, Error, Term};
-use std::collections::HashMap;
+use crate::handles::{HandleId, Handles};
+use deno_core::serde_json::Value;
+use deno_core::{anyhow, v8};
+use rustler::env::SavedTerm;
+use rustler::types::tuple::{get_tuple, make_tuple};
+use rustler::types::{ListIterator, MapIterator};
+use rustler::{
+    types::atom, Atom, Binary, Decoder, Encoder, Env, Error, LocalPid, NewBinary, NifResult,
+    OwnedEnv, ResourceArc, Term,
+};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+// Objects standing for a term JavaScript has no value for, such as a tagged
+// tuple, are marked with this key
+pub const TAG_KEY: &str = "__jsengine__";
+
+// Largest integer a JS number holds exactly, `Number.MAX_SAFE_INTEGER`
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+// How deeply values may nest either way, well within the engine thread's stack
+const MAX_DEPTH: usize = 256;
+
+/// Terms copied out of a NIF call, so the engine thread can read them straight
+/// into V8.
+pub(crate) struct Terms {
+    env: OwnedEnv,
+    terms: Vec<SavedTerm>,
+    opts: ExecOptions,
+}
+
+impl Terms {
+    pub fn new<'a>(terms: impl IntoIterator<Item = Term<'a>>, opts: &ExecOptions) -> Self {
+        let env = OwnedEnv::new();
+        let terms = terms.into_iter().map(|term| env.save(term)).collect();
+        Terms {
+            env,
+            terms,
+            opts: opts.clone(),
+        }
+    }
+
+    pub fn to_v8<'s>(
+        &self,
+        scope: &mut v8::HandleScope<'s>,
+    ) -> Result<Vec<v8::Local<'s, v8::Value>>, Value> {
+        self.env.run(|env| {
+            self.terms
+                .iter()
+                .map(|term| term_to_v8(scope, term.load(env), &self.opts, 0))
+                .collect()
+        })
+    }
+}
+
+/// A value converted for Elixir on the engine thread. Its terms are built in an
+/// environment of their own, and copied into whichever one they are returned
+/// or sent from.
+pub(crate) struct Exported {
+    env: OwnedEnv,
+    term: SavedTerm,
+    // Whether it holds tagged handles, which only the NIF side can create
+    tagged: bool,
+}
+
+impl Exported {
+    fn build<F>(build: F) -> Self
+    where
+        F: for<'a> FnOnce(Env<'a>) -> Term<'a>,
+    {
+        let env = OwnedEnv::new();
+        let term = env.run(|term_env| env.save(build(term_env)));
+        Exported {
+            env,
+            term,
+            tagged: false,
+        }
+    }
+
+    fn try_build<F>(build: F) -> Result<Self, Value>
+    where
+        F: for<'a> FnOnce(Env<'a>) -> Result<Term<'a>, Value>,
+    {
+        let env = OwnedEnv::new();
+        let term = env.run(|term_env| build(term_env).map(|term| env.save(term)))?;
+        Ok(Exported {
+            env,
+            term,
+            tagged: false,
+        })
+    }
+
+    pub fn nil() -> Self {
+        Exported::build(|env| atom::nil().encode(env))
+    }
+
+    // A value kept alive in an engine under `handle`
+    pub fn handle(kind: &str, handle: HandleId) -> Self {
+        Exported {
+            tagged: true,
+            ..Exported::build(|env| tagged(env, kind, handle))
+        }
+    }
+
+    pub fn is_tagged(&self) -> bool {
+        self.tagged
+    }
+
+    pub fn load<'a>(&self, env: Env<'a>) -> Term<'a> {
+        self.env.run(|own_env| self.term.load(own_env).in_env(env))
+    }
+}
+
+// Values with no term of their own, such as functions kept alive in an engine,
+// are exported as `{:jsengine, kind, handle}`. No JavaScript value converts to
+// a tuple holding an atom, so a script can't forge one to release another's
+// handle.
+fn tagged<'a>(env: Env<'a>, kind: &str, handle: HandleId) -> Term<'a> {
+    (atoms::jsengine(), kind, handle).encode(env)
+}
+
+/// Structs that objects naming one in `__struct__` are decoded back into, kept
+/// as their default values in the external term format and keyed by module
+/// name: those listed in `:structs`, or those of any loaded module, looked up
+/// as objects name them.
+#[derive(Clone, Debug)]
+pub enum Structs {
+    Listed(Arc<HashMap<String, Vec<u8>>>),
+    Loaded(StructResolver),
+}
+
+impl Default for Structs {
+    fn default() -> Self {
+        Structs::Listed(Arc::default())
+    }
+}
+
+impl Structs {
+    fn is_empty(&self) -> bool {
+        matches!(self, Structs::Listed(structs) if structs.is_empty())
+    }
+
+    fn default_for<'a>(&self, env: Env<'a>, name: &str) -> Option<Term<'a>> {
+        let decode = |default: &[u8]| env.binary_to_term(default).map(|(term, _)| term);
+        match self {
+            Structs::Listed(structs) => decode(structs.get(name)?),
+            Structs::Loaded(resolver) => decode(&resolver.resolve(name)?),
+        }
+    }
+}
+
+// Passed from Elixir as a list of default structs, e.g. `[%MyApp.User{}]`, or
+// as the pid of the process that looks up loaded ones
+impl<'a> Decoder<'a> for Structs {
+    fn decode(term: Term<'a>) -> NifResult<Self> {
+        if let Ok(pid) = term.decode::<LocalPid>() {
+            return Ok(Structs::Loaded(StructResolver::new(pid)));
+        }
+        let structs = term
+            .decode::<Vec<Term>>()?
+            .into_iter()
+            .map(|default| {
+                let module = default.map_get(atoms::__struct__())?;
+                let name = module_name(module).ok_or(Error::BadArg)?;
+                Ok((name, default.to_binary().as_slice().to_vec()))
+            })
+            .collect::<NifResult<HashMap<_, _>>>()?;
+        Ok(Structs::Listed(Arc::new(structs)))
+    }
+}
+
+impl Encoder for Structs {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            Structs::Listed(structs) => structs
+                .keys()
+                .filter_map(|name| self.default_for(env, name))
+                .collect::<Vec<_>>()
+                .encode(env),
+            Structs::Loaded(resolver) => resolver.pid.encode(env),
+        }
+    }
+}
+
+// How long to wait for the resolver before treating a module as not loaded
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Looks up the structs of loaded modules through a process on the Elixir
+/// side, as only Elixir can tell which modules are loaded. Results without a
+/// `__struct__` key cost nothing, and each module is only asked about once
+/// per request.
+#[derive(Clone)]
+pub struct StructResolver {
+    pid: LocalPid,
+    found: Arc<Mutex<Found>>,
+}
+
+// Defaults looked up so far by module name, `None` where there is no struct
+type Found = HashMap<String, Option<Arc<Vec<u8>>>>;
+
+impl StructResolver {
+    fn new(pid: LocalPid) -> Self {
+        StructResolver {
+            pid,
+            found: Arc::default(),
+        }
+    }
+
+    fn resolve(&self, name: &str) -> Option<Arc<Vec<u8>>> {
+        let mut found = self.found.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(default) = found.get(name) {
+            return default.clone();
+        }
+        let default = self.ask(name).map(Arc::new);
+        found.insert(name.to_string(), default.clone());
+        default
+    }
+
+    // Sends {:lookup_struct, name, lookup} and waits for the answer. A
+    // resolver that is gone drops the lookup unanswered, which ends the wait.
+    fn ask(&self, name: &str) -> Option<Vec<u8>> {
+        let (sender, receiver) = channel();
+        let lookup = ResourceArc::new(StructLookup(Mutex::new(Some(sender))));
+        let mut msg_env = OwnedEnv::new();
+        msg_env
+            .send_and_clear(&self.pid, |env| {
+                (atoms::lookup_struct(), name, lookup).encode(env)
+            })
+            .ok()?;
+        receiver.recv_timeout(RESOLVE_TIMEOUT).ok().flatten()
+    }
+}
+
+impl fmt::Debug for StructResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StructResolver").finish_non_exhaustive()
+    }
+}
+
+/// A lookup sent to the struct resolver, answered with `struct_resolved/2`
+pub struct StructLookup(Mutex<Option<Sender<Option<Vec<u8>>>>>);
+
+impl StructLookup {
+    pub fn answer(&self, default: Option<Vec<u8>>) {
+        let sender = self.0.lock().unwrap_or_else(PoisonError::into_inner).take();
+        if let Some(sender) = sender {
+            let _ = sender.send(default);
+        }
+    }
+}
+
+// A module's name as JavaScript sees it, without the "Elixir." prefix
+fn module_name(module: Term) -> Option<String> {
+    let name = module.atom_to_string().ok()?;
+    match name.strip_prefix("Elixir.") {
+        Some(name) => Some(name.to_string()),
+        None => Some(name),
+    }
+}
+
+// Builds a map in one go, unless keys collide, as `NaN` and `null` Map keys
+// both becoming `nil` do; then the last one wins
+fn make_map<'a>(env: Env<'a>, keys: &[Term<'a>], values: &[Term<'a>]) -> Term<'a> {
+    Term::map_from_term_arrays(env, keys, values).unwrap_or_else(|_| {
+        keys.iter()
+            .zip(values)
+            .fold(Term::map_new(env), |map, (key, value)| {
+                map.map_put(*key, *value).unwrap_or(map)
+            })
+    })
+}
+
+// Converts a value for Elixir: functions are kept in `handles` and come out
+// tagged, typed arrays and `ArrayBuffer`s as `{:binary, data}`, `BigInt`s as
+// integers, `Map`s as maps, and objects naming one of `structs` as that struct
+pub fn v8_to_term(
+    scope: &mut v8::HandleScope,
+    value: v8::Local<v8::Value>,
+    handles: &mut Handles,
+    structs: &Structs,
+) -> Result<Exported, Value> {
+    let mut has_tags = false;
+    let exported = Exported::try_build(|env| {
+        build_term(scope, env, value, handles, structs, &mut has_tags, 0)
+    })?;
+    Ok(Exported {
+        tagged: has_tags,
+        ..exported
+    })
+}
+
+fn build_term<'a>(
+    scope: &mut v8::HandleScope,
+    env: Env<'a>,
+    value: v8::Local<v8::Value>,
+    handles: &mut Handles,
+    structs: &Structs,
+    has_tags: &mut bool,
+    depth: usize,
+) -> Result<Term<'a>, Value> {
+    // Objects that refer to themselves would otherwise recurse until the
+    // stack overflows, taking the whole VM down with it
+    if depth > MAX_DEPTH {
+        return Err(Value::String(format!(
+            "Cannot convert a value nested more than {} levels deep; it may refer to itself",
+            MAX_DEPTH
+        )));
+    }
+
+    if value.is_null_or_undefined() {
+        return Ok(atom::nil().encode(env));
+    }
+
+    if value.is_boolean() {
+        return Ok(value.is_true().encode(env));
+    }
+
+    // Integers come out as such only when V8 holds them as 32-bit ones, as
+    // serde_v8 did
+    if value.is_int32() || value.is_uint32() {
+        return Ok(value.integer_value(scope).unwrap_or(0).encode(env));
+    }
+
+    if let Ok(number) = v8::Local::<v8::Number>::try_from(value) {
+        return Ok(match number.value() {
+            number if number.is_finite() => number.encode(env),
+            _ => atom::nil().encode(env),
+        });
+    }
+
+    if let Ok(string) = v8::Local::<v8::String>::try_from(value) {
+        return Ok(string_to_term(scope, env, string));
+    }
+
+    if value.is_function() {
+        *has_tags = true;
+        return Ok(tagged(env, "function", handles.insert(scope, value)));
+    }
+
+    if let Ok(bigint) = v8::Local::<v8::BigInt>::try_from(value) {
+        let mut words = vec![0; bigint.word_count()];
+        let (negative, words) = bigint.to_words_array(&mut words);
+        return Ok(bigint_to_term(env, negative, words));
+    }
+
+    if let Some(binary) = binary_to_term(scope, env, value) {
+        return Ok(binary);
+    }
+
+    if let Ok(array) = v8::Local::<v8::Array>::try_from(value) {
+        let mut items = Vec::with_capacity(array.length() as usize);
+        for index in 0..array.length() {
+            let item = array
+                .get_index(scope, index)
+                .unwrap_or_else(|| v8::undefined(scope).into());
+            items.push(build_term(
+                scope,
+                env,
+                item,
+                handles,
+                structs,
+                has_tags,
+                depth + 1,
+            )?);
+        }
+        return Ok(items.encode(env));
+    }
+
+    // A `Map` flattens to its entries, so keys of any type survive
+    if let Ok(map) = v8::Local::<v8::Map>::try_from(value) {
+        let entries = map.as_array(scope);
+        let mut keys = Vec::with_capacity(map.size());
+        let mut values = Vec::with_capacity(map.size());
+        for index in 0..entries.length() {
+            let entry = entries
+                .get_index(scope, index)
+                .unwrap_or_else(|| v8::undefined(scope).into());
+            let entry = build_term(scope, env, entry, handles, structs, has_tags, depth + 1)?;
+            match index % 2 {
+                0 => keys.push(entry),
+                _ => values.push(entry),
+            }
+        }
+        return Ok(make_map(env, &keys, &values));
+    }
+
+    if let Ok(object) = v8::Local::<v8::Object>::try_from(value) {
+        if let Some(items) = tuple_items(scope, object) {
+            let items = (0..items.length())
+                .map(|index| {
+                    let item = items
+                        .get_index(scope, index)
+                        .unwrap_or_else(|| v8::undefined(scope).into());
+                    build_term(scope, env, item, handles, structs, has_tags, depth + 1)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(make_tuple(env, &items));
+        }
+
+        if let Some(default) = struct_default(scope, env, object, structs) {
+            let mut keys = Vec::new();
+            let mut values = Vec::new();
+            for (field, value) in default.decode::<MapIterator>().into_iter().flatten() {
+                let item = match field.atom_to_string() {
+                    Ok(name) if !atoms::__struct__().eq(&field) => {
+                        own_property(scope, object, &name)
+                    }
+                    _ => None,
+                };
+                keys.push(field);
+                values.push(match item {
+                    Some(item) => {
+                        build_term(scope, env, item, handles, structs, has_tags, depth + 1)?
+                    }
+                    None => value,
+                });
+            }
+            return Ok(make_map(env, &keys, &values));
+        }
+
+        let args = v8::GetPropertyNamesArgsBuilder::new()
+            .key_conversion(v8::KeyConversionMode::ConvertToString)
+            .build();
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        if let Some(names) = object.get_own_property_names(scope, args) {
+            for index in 0..names.length() {
+                let Some(key) = names.get_index(scope, index) else {
+                    continue;
+                };
+                let Some(name) = key.to_string(scope) else {
+                    continue;
+                };
+                let item = object
+                    .get(scope, key)
+                    .unwrap_or_else(|| v8::undefined(scope).into());
+                keys.push(string_to_term(scope, env, name));
+                values.push(build_term(
+                    scope,
+                    env,
+                    item,
+                    handles,
+                    structs,
+                    has_tags,
+                    depth + 1,
+                )?);
+            }
+        }
+        return Ok(make_map(env, &keys, &values));
+    }
+
+    // Symbols and anything else with no counterpart
+    Ok(atom::nil().encode(env))
+}
+
+// The items of an object tagged as a tuple, as `Tuples::Tagged` passes them
+fn tuple_items<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    object: v8::Local<v8::Object>,
+) -> Option<v8::Local<'s, v8::Array>> {
+    let kind = own_property(scope, object, TAG_KEY)?;
+    if kind.to_rust_string_lossy(scope) != "tuple" {
+        return None;
+    }
+    let key = v8::String::new(scope, "items")?;
+    object.get(scope, key.into())?.try_into().ok()
+}
+
+// The default value of the struct an object names in `__struct__`, if that
+// struct is one of `structs`
+fn struct_default<'a>(
+    scope: &mut v8::HandleScope,
+    env: Env<'a>,
+    object: v8::Local<v8::Object>,
+    structs: &Structs,
+) -> Option<Term<'a>> {
+    if structs.is_empty() {
+        return None;
+    }
+    let name = own_property(scope, object, "__struct__")?;
+    let name = v8::Local::<v8::String>::try_from(name).ok()?;
+    structs.default_for(env, &name.to_rust_string_lossy(scope))
+}
+
+fn own_property<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    object: v8::Local<v8::Object>,
+    name: &str,
+) -> Option<v8::Local<'s, v8::Value>> {
+    let key = v8::String::new(scope, name)?;
+    if !object.has_own_property(scope, key.into())? {
+        return None;
+    }
+    object.get(scope, key.into())
+}
+
+// Writes a string's UTF-8 straight into a new binary
+fn string_to_term<'a>(
+    scope: &mut v8::HandleScope,
+    env: Env<'a>,
+    string: v8::Local<v8::String>,
+) -> Term<'a> {
+    let mut binary = NewBinary::new(env, string.utf8_length(scope));
+    string.write_utf8(
+        scope,
+        binary.as_mut_slice(),
+        None,
+        v8::WriteOptions::NO_NULL_TERMINATION | v8::WriteOptions::REPLACE_INVALID_UTF8,
+    );
+    binary.into()
+}
+
+// Copies the bytes of a typed array, `DataView` or `ArrayBuffer` into a binary,
+// wrapped as `{:binary, data}` so it can't be mistaken for a string
+fn binary_to_term<'a>(
+    scope: &mut v8::HandleScope,
+    env: Env<'a>,
+    value: v8::Local<v8::Value>,
+) -> Option<Term<'a>> {
+    let view = match v8::Local::<v8::ArrayBufferView>::try_from(value) {
+        Ok(view) => view,
+        Err(_) => {
+            let buffer = v8::Local::<v8::ArrayBuffer>::try_from(value).ok()?;
+            v8::Uint8Array::new(scope, buffer, 0, buffer.byte_length())?.into()
+        }
+    };
+    let mut binary = NewBinary::new(env, view.byte_length());
+    view.copy_contents(binary.as_mut_slice());
+    let binary: Term = binary.into();
+    Some((atoms::binary(), binary).encode(env))
+}
+
+// Converts a term for JavaScript: strings and atoms become strings, binaries
+// that aren't UTF-8 or are wrapped as `{:binary, data}` become `Uint8Array`s,
+// integers too big for a number become `BigInt`s, keyword lists become
+// objects and other tuples arrays, unless `opts` asks for them tagged
+fn term_to_v8<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    term: Term,
+    opts: &ExecOptions,
+    depth: usize,
+) -> Result<v8::Local<'s, v8::Value>, Value> {
+    if depth > MAX_DEPTH {
+        return Err(Value::String(format!(
+            "Cannot convert a term nested more than {} levels deep",
+            MAX_DEPTH
+        )));
+    }
+
+    if term.is_atom() {
+        if atoms::true_().eq(&term) {
+            return Ok(v8::Boolean::new(scope, true).into());
+        } else if atoms::false_().eq(&term) {
+            return Ok(v8::Boolean::new(scope, false).into());
+        } else if atoms::nil().eq(&term) {
+            return Ok(v8::null(scope).into());
+        }
+        let name = term_to_string(&term).map_err(|_| unsupported(term))?;
+        return string_to_v8(scope, name.as_bytes());
+    }
+
+    if let Ok(data) = term.decode::<Binary>() {
+        return match std::str::from_utf8(data.as_slice()) {
+            Ok(string) => string_to_v8(scope, string.as_bytes()),
+            Err(_) => bytes_to_v8(scope, data.as_slice()),
+        };
+    }
+
+    if term.is_number() {
+        if let Ok(i) = term.decode::<i64>() {
+            if opts.bigint && i.unsigned_abs() > MAX_SAFE_INTEGER {
+                return Ok(v8::BigInt::new_from_i64(scope, i).into());
+            }
+            return Ok(v8::Number::new(scope, i as f64).into());
+        }
+        if let Ok(f) = term.decode::<f64>() {
+            return Ok(v8::Number::new(scope, f).into());
+        }
+        // Integers too big for i64 are the last thing left to try
+        if let Some((negative, words)) = bignum_words(term) {
+            return v8::BigInt::new_from_words(scope, negative, &words)
+                .map(Into::into)
+                .ok_or_else(|| Value::String("Error creating BigInt".to_string()));
+        }
+    }
+
+    if term.is_list() || term.is_empty_list() {
+        let items = term.decode::<Vec<Term>>().map_err(|_| unsupported(term))?;
+        if is_keyword(&items) {
+            let entries = items.iter().filter_map(|item| get_tuple(*item).ok());
+            let entries = entries.map(|pair| (pair[0], pair[1]));
+            return object_to_v8(scope, entries, opts, depth);
+        }
+        let items = items
+            .into_iter()
+            .map(|item| term_to_v8(scope, item, opts, depth + 1))
+            .collect::<Result<Vec<_>, _>>()?;
+        return Ok(v8::Array::new_with_elements(scope, &items).into());
+    }
+
+    if let Ok(entries) = term.decode::<MapIterator>() {
+        return object_to_v8(scope, entries, opts, depth);
+    }
+
+    // `{:binary, data}` marks bytes that happen to be valid UTF-8 too
+    if let Ok((tag, data)) = term.decode::<(Atom, Binary)>() {
+        if atoms::binary().eq(&tag) {
+            return bytes_to_v8(scope, data.as_slice());
+        }
+    }
+
+    if let Ok(items) = get_tuple(term) {
+        let items = items
+            .into_iter()
+            .map(|item| term_to_v8(scope, item, opts, depth + 1))
+            .collect::<Result<Vec<_>, _>>()?;
+        let items = v8::Array::new_with_elements(scope, &items);
+        return match opts.tuples {
+            Tuples::Array => Ok(items.into()),
+            Tuples::Tagged => {
+                let tag = string_to_v8(scope, TAG_KEY.as_bytes())?;
+                let kind = string_to_v8(scope, b"tuple")?;
+                let key = string_to_v8(scope, b"items")?;
+                let object = v8::Object::new(scope);
+                object.set(scope, tag, kind);
+                object.set(scope, key, items.into());
+                Ok(object.into())
+            }
+        };
+    }
+
+    Err(unsupported(term))
+}
+
+// A non-empty list of `{atom, value}` pairs with distinct keys, like
+// `[name: "x", limit: 5]`. Lists repeating a key, like `[{:ok, 1}, {:ok, 2}]`,
+// stay arrays so that no value is lost.
+fn is_keyword(items: &[Term]) -> bool {
+    let mut keys = HashSet::new();
+    !items.is_empty()
+        && items.iter().all(|item| match get_tuple(*item) {
+            Ok(pair) => pair.len() == 2 && pair[0].is_atom() && keys.insert(pair[0]),
+            Err(_) => false,
+        })
+}
+
+// Builds an object from a map's or keyword list's entries. A key that comes
+// up twice, like `:a` and `"a"` in one map, keeps its first value.
+fn object_to_v8<'a, 's>(
+    scope: &mut v8::HandleScope<'s>,
+    entries: impl Iterator<Item = (Term<'a>, Term<'a>)>,
+    opts: &ExecOptions,
+    depth: usize,
+) -> Result<v8::Local<'s, v8::Value>, Value> {
+    let object = v8::Object::new(scope);
+    for (key, item) in entries {
+        // Structs name their module as JavaScript sees it, e.g. "MyApp.User"
+        let module = match atoms::__struct__().eq(&key) {
+            true => module_name(item),
+            false => None,
+        };
+        let key = object_key(scope, key)?;
+        if object.has_own_property(scope, key.into()) == Some(true) {
+            continue;
+        }
+        let item = match module {
+            Some(module) => string_to_v8(scope, module.as_bytes())?,
+            None => term_to_v8(scope, item, opts, depth + 1)?,
+        };
+        object.create_data_property(scope, key.into(), item);
+    }
+    Ok(object.into())
+}
+
+fn object_key<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    key: Term,
+) -> Result<v8::Local<'s, v8::String>, Value> {
+    let name = match key.decode::<&str>() {
+        Ok(name) => name.to_string(),
+        Err(_) => term_to_string(&key).map_err(|_| {
+            Value::String(format!(
+                "Cannot use {:?} as an object key; keys must be strings or atoms",
+                key
+            ))
+        })?,
+    };
+    v8::String::new(scope, &name)
+        .ok_or_else(|| Value::String(format!("Error creating V8 string from {}", name)))
+}
+
+fn string_to_v8<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    bytes: &[u8],
+) -> Result<v8::Local<'s, v8::Value>, Value> {
+    v8::String::new_from_utf8(scope, bytes, v8::NewStringType::Normal)
+        .map(Into::into)
+        .ok_or_else(|| Value::String("Error creating V8 string".to_string()))
+}
+
+fn bytes_to_v8<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    bytes: &[u8],
+) -> Result<v8::Local<'s, v8::Value>, Value> {
+    let store = v8::ArrayBuffer::new_backing_store_from_vec(bytes.to_vec()).make_shared();
+    let buffer = v8::ArrayBuffer::with_backing_store(scope, &store);
+    v8::Uint8Array::new(scope, buffer, 0, bytes.len())
+        .map(Into::into)
+        .ok_or_else(|| Value::String("Error creating Uint8Array".to_string()))
+}
+
+fn unsupported(term: Term) -> Value {
+    Value::String(format!("Cannot convert {:?} to a JavaScript value", term))
+}
+
+// Turns a handle tag into a term, or declines so it stays a plain tuple
+pub type DecodeTagged<'d, 'a> = dyn FnMut(Env<'a>, &str, HandleId) -> Option<Term<'a>> + 'd;
+
+// Replaces the handle tags in an exported term, wherever they are nested
+pub fn decode_tagged<'a>(
+    env: Env<'a>,
+    term: Term<'a>,
+    decode: &mut DecodeTagged<'_, 'a>,
+) -> Term<'a> {
+    if let Ok(items) = term.decode::<ListIterator>() {
+        return items
+            .map(|item| decode_tagged(env, item, decode))
+            .collect::<Vec<_>>()
+            .encode(env);
+    }
+
+    if let Ok(entries) = term.decode::<MapIterator>() {
+        let (keys, values): (Vec<_>, Vec<_>) = entries
+            .map(|(key, value)| {
+                let key = decode_tagged(env, key, decode);
+                (key, decode_tagged(env, value, decode))
+            })
+            .unzip();
+        return make_map(env, &keys, &values);
+    }
+
+    if let Ok(items) = get_tuple(term) {
+        if let Ok((tag, kind, handle)) = term.decode::<(Atom, &str, HandleId)>() {
+            if atoms::jsengine().eq(&tag) {
+                if let Some(decoded) = decode(env, kind, handle) {
+                    return decoded;
+                }
+            }
+        }
+        let items = items
+            .into_iter()
+            .map(|item| decode_tagged(env, item, decode))
+            .collect::<Vec<_>>();
+        return make_tuple(env, &items);
+    }
+
+    term
+}
 
 pub fn json_to_term<'a>(env: Env<'a>, value: &Value) -> Term<'a> {
     match value {
@@ -35,110 +792,54 @@ pub fn json_to_term<'a>(env: Env<'a>, value: &Value) -> Term<'a> {
     }
 }
 
-#[allow(clippy::only_used_in_recursion)]
-pub fn term_to_json(env: Env, term: Term) -> Result<Value, rustler::Error> {
-    if let Ok(atom) = term.decode::<Atom>() {
-        if atoms::true_().eq(&atom) {
-            return Ok(Value::Bool(true));
-        } else if atoms::false_().eq(&atom) {
-            return Ok(Value::Bool(false));
-        } else if atoms::nil().eq(&atom) {
-            return Ok(Value::Null);
-        } else {
-            // Handle other atoms as strings
-            match term_to_string(&term) {
-                Ok(s) => return Ok(Value::String(s)),
-                Err(_) => return Err(Error::Atom("invalid_atom")),
-            }
+// rustler has no big integer type, so integers past 64 bits are built from and
+// read as the external term format, where they are little-endian bytes
+fn bigint_to_term<'a>(env: Env<'a>, negative: bool, words: &[u64]) -> Term<'a> {
+    if words.len() <= 1 {
+        let magnitude = words.first().copied().unwrap_or(0) as i128;
+        let value = if negative { -magnitude } else { magnitude };
+        if let Ok(value) = i64::try_from(value) {
+            return value.encode(env);
         }
     }
-    if let Ok(s) = term.decode::<String>() {
-        return Ok(Value::String(s));
-    }
-    if let Ok(i) = term.decode::<i64>() {
-        return Ok(Value::Number(i.into()));
-    }
-    if let Ok(f) = term.decode::<f64>() {
-        // from_f64 returns None for NaN and Infinity
-        if let Some(num) = serde_json::Number::from_f64(f) {
-            return Ok(Value::Number(num));
-        } else {
-            // Handle special float values as null (could also return error)
-            return Ok(Value::Null);
-        }
-    }
-    if let Ok(list) = term.decode::<Vec<Term>>() {
-        let json_list: Result<Vec<_>, _> =
-            list.iter().map(|item| term_to_json(env, *item)).collect();
-        return Ok(Value::Array(json_list?));
+
+    let mut digits = words
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect::<Vec<u8>>();
+    while digits.last() == Some(&0) {
+        digits.pop();
     }
-    if let Ok(map) = term.decode::<std::collections::HashMap<Term, Term>>() {
-        let mut json_map = serde_json::Map::new();
-        for (key, value) in map {
-            let key_json = term_to_json(env, key)?;
-            // Map keys must be strings in JSON
-            let key_str = match key_json {
-                Value::String(s) => s,
-                _ => return Err(Error::Atom("map_keys_must_be_strings")),
-            };
-            json_map.insert(key_str, term_to_json(env, value)?);
-        }
-        return Ok(Value::Object(json_map));
+    let mut etf = vec![131, 111];
+    etf.extend((digits.len() as u32).to_be_bytes());
+    etf.push(negative as u8);
+    etf.extend(digits);
+    match env.binary_to_term(&etf) {
+        Some((term, _)) => term,
+        None => atom::nil().encode(env),
     }
-    // Handle other types or return an error
-    Err(Error::Atom("invalid_type"))
 }
 
-pub fn serde_v8_error_to_json(error: &SerdeV8Error) -> Value {
-    match error {
-        SerdeV8Error::Message(msg) => json!({"type": "Message", "message": msg}),
-        SerdeV8Error::ExpectedBoolean(expected) => {
-            json!({"type": "ExpectedBoolean", "expected": expected})
-        }
-        SerdeV8Error::ExpectedInteger(expected) => {
-            json!({"type": "ExpectedInteger", "expected": expected})
-        }
-        SerdeV8Error::ExpectedNumber(expected) => {
-            json!({"type": "ExpectedNumber", "expected": expected})
+fn bignum_words(term: Term) -> Option<(bool, Vec<u64>)> {
+    let etf = term.to_binary();
+    let (sign, digits) = match etf.as_slice() {
+        [131, 110, n, sign, digits @ ..] if digits.len() == *n as usize => (*sign, digits),
+        [131, 111, a, b, c, d, sign, digits @ ..]
+            if digits.len() == u32::from_be_bytes([*a, *b, *c, *d]) as usize =>
+        {
+            (*sign, digits)
         }
-        SerdeV8Error::ExpectedString(expected) => {
-            json!({"type": "ExpectedString", "expected": expected})
-        }
-        SerdeV8Error::ExpectedArray(expected) => {
-            json!({"type": "ExpectedArray", "expected": expected})
-        }
-        SerdeV8Error::ExpectedMap(expected) => {
-            json!({"type": "ExpectedMap", "expected": expected})
-        }
-        SerdeV8Error::ExpectedEnum(expected) => {
-            json!({"type": "ExpectedEnum", "expected": expected})
-        }
-        SerdeV8Error::ExpectedObject(expected) => {
-            json!({"type": "ExpectedObject", "expected": expected})
-        }
-        SerdeV8Error::ExpectedBuffer(expected) => {
-            json!({"type": "ExpectedBuffer", "expected": expected})
-        }
-        SerdeV8Error::ExpectedDetachable(expected) => {
-            json!({"type": "ExpectedDetachable", "expected": expected})
-        }
-        SerdeV8Error::ExpectedExternal(expected) => {
-            json!({"type": "ExpectedExternal", "expected": expected})
-        }
-        SerdeV8Error::ExpectedBigInt(expected) => {
-            json!({"type": "ExpectedBigInt", "expected": expected})
-        }
-        SerdeV8Error::ExpectedUtf8 => json!({"type": "ExpectedUtf8"}),
-        SerdeV8Error::ExpectedLatin1 => json!({"type": "ExpectedLatin1"}),
-        SerdeV8Error::UnsupportedType => json!({"type": "UnsupportedType"}),
-        SerdeV8Error::LengthMismatch(got, expected) => {
-            json!({"type": "LengthMismatch", "got": got, "expected": expected})
-        }
-        SerdeV8Error::ResizableBackingStoreNotSupported => {
-            json!({"type": "ResizableBackingStoreNotSupported"})
-        }
-        &_ => json!({"type": "error::unknown"}),
-    }
+        _ => return None,
+    };
+    let words = digits
+        .chunks(8)
+        .map(|chunk| {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            u64::from_le_bytes(word)
+        })
+        .collect();
+    Some((sign == 1, words))
 }
 
 pub fn anyhow_error_to_json(error: &anyhow::Error) -> Value {
@@ -1,30 +1,199 @@
-use crate::conv::{anyhow_error_to_json, serde_v8_error_to_json};
+use crate::conv::{anyhow_error_to_json, v8_to_term, Exported, Structs, Terms};
+use crate::handles::{HandleId, Handles};
+use crate::interrupt::{Interrupt, Termination, Watchdog};
+use crate::streams::{Stream, StreamId, StreamStart, Streams};
 
 use deno_ast::{EmitOptions, MediaType, ParseParams};
-use deno_core::error::AnyError;
-use deno_core::serde_json::Value;
+use deno_core::serde_json::{self, Value};
 use deno_core::{
-    anyhow, op2, serde_v8, v8, Extension, FastString, FsModuleLoader, JsRuntime, ModuleCode,
-    ModuleSpecifier, Op, RuntimeOptions,
+    futures, op2, serde_v8, v8, CancelFuture, CancelHandle, Extension, FastString, FsModuleLoader,
+    JsRuntime, JsRuntimeForSnapshot, ModuleCode, ModuleId, ModuleSpecifier, Op, OpState,
+    RuntimeOptions, Snapshot,
 };
+use rustler::{NifMap, NifUnitEnum};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::future::poll_fn;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::task::Poll;
+use std::thread;
+use std::time::Duration;
 
-pub(crate) type JsResult = Result<Value, Value>;
+pub(crate) type JsResult = Result<Exported, Value>;
 pub(crate) type EnvId = u64;
+pub(crate) type PoolId = u64;
+pub(crate) type RequestId = u64;
+
+// Values made visible to a single evaluation as local variables, by name
+pub(crate) struct Bindings {
+    pub names: Vec<String>,
+    pub values: Terms,
+}
+
+// Per-request execution options, passed from Elixir as a map with every key present
+#[derive(Clone, Debug, NifMap)]
+pub struct ExecOptions {
+    pub timeout_ms: Option<u64>,
+    pub returns: Returns,
+    // Whether a returned promise is waited on, or handed back still pending
+    pub awaits: bool,
+    // Whether integers past `Number.MAX_SAFE_INTEGER` are passed as `BigInt`
+    pub bigint: bool,
+    pub tuples: Tuples,
+    // Structs objects naming one in `__struct__` may be decoded back into
+    pub structs: Structs,
+}
+
+impl Default for ExecOptions {
+    fn default() -> Self {
+        ExecOptions {
+            timeout_ms: None,
+            returns: Returns::default(),
+            awaits: true,
+            bigint: false,
+            tuples: Tuples::default(),
+            structs: Structs::default(),
+        }
+    }
+}
+
+// How a result is handed back: converted to Elixir terms, or left in the
+// engine and returned as a handle
+#[derive(Clone, Copy, Debug, Default, NifUnitEnum)]
+pub enum Returns {
+    #[default]
+    Value,
+    Handle,
+}
+
+// How tuples are passed to JavaScript: as arrays, or as objects tagged so
+// they come back as tuples
+#[derive(Clone, Copy, Debug, Default, NifUnitEnum)]
+pub enum Tuples {
+    #[default]
+    Array,
+    Tagged,
+}
+
+// Where a promise handed back with `await: false` has got to
+#[derive(Clone, Copy, Debug, NifUnitEnum)]
+pub enum PromiseStatus {
+    Pending,
+    Fulfilled,
+    Rejected,
+}
+
+// How `inject_fault/2` makes an environment's thread fail: by panicking, or by
+// exiting as if it had died
+#[derive(Clone, Copy, Debug, NifUnitEnum)]
+pub enum Fault {
+    Panic,
+    Exit,
+}
+
+// Per-environment settings, fixed when the environment is created
+#[derive(Clone, Debug, Default, NifMap)]
+pub struct EnvOptions {
+    pub initial_heap_size: Option<usize>,
+    pub max_heap_size: Option<usize>,
+    pub snapshot: Option<String>,
+    // Loaded whenever the environment starts, including after a reset
+    pub preload: Vec<String>,
+}
+
+// Everything needed to build an environment's engine. Files are read up front,
+// so a bad snapshot path fails environment creation rather than first use.
+#[derive(Clone, Default)]
+pub(crate) struct EnvConfig {
+    pub options: EnvOptions,
+    pub snapshot: Option<Arc<[u8]>>,
+}
+
+impl EnvConfig {
+    pub fn new(options: EnvOptions) -> Result<Self, Value> {
+        let snapshot = match &options.snapshot {
+            Some(path) => Some(Arc::from(std::fs::read(path).map_err(|e| {
+                Value::String(format!("Failed to read snapshot '{}': {}", path, e))
+            })?)),
+            None => None,
+        };
+        Ok(EnvConfig { options, snapshot })
+    }
+}
 
 pub enum Request {
-    CreateEnv,
+    CreateEnv(EnvConfig),
     DestroyEnv(EnvId),
-    Load(EnvId, Vec<String>),
-    Run(EnvId, String),
-    Call(EnvId, String, Vec<Value>),
+    ResetEnv(EnvId),
+    Load(EnvId, Vec<String>, ExecOptions),
+    Run(EnvId, String, Bindings, ExecOptions),
+    Call(EnvId, Vec<String>, Terms, ExecOptions),
+    CallMany(EnvId, Vec<(Vec<String>, Terms)>, ExecOptions),
+    Construct(EnvId, Vec<String>, Terms, ExecOptions),
+    StreamCall(EnvId, StreamStart, ExecOptions),
+    StreamDemand(EnvId, StreamId, usize, ExecOptions),
+    StreamClose(EnvId, StreamId),
+    CallExport(EnvId, String, Vec<String>, Terms, ExecOptions),
+    CallHandle(EnvId, HandleId, Terms, ExecOptions),
+    GetProperty(EnvId, HandleId, Vec<String>, ExecOptions),
+    SetProperty(EnvId, HandleId, Vec<String>, Terms),
+    InvokeMethod(EnvId, HandleId, Vec<String>, Terms, ExecOptions),
+    GetGlobal(EnvId, Vec<String>, ExecOptions),
+    SetGlobal(EnvId, Vec<String>, Terms),
+    AwaitPromise(EnvId, HandleId, ExecOptions),
+    PromiseState(EnvId, HandleId),
+    Release(EnvId, HandleId),
+    CreatePool(usize, EnvConfig),
+    Checkout(PoolId),
+    Checkin(PoolId, EnvId),
+    Cancel(EnvId, RequestId),
+    #[cfg(feature = "fault-injection")]
+    InjectFault(EnvId, Fault),
+}
+
+impl Request {
+    fn options(&self) -> Option<&ExecOptions> {
+        match self {
+            Request::Load(_, _, opts)
+            | Request::Run(_, _, _, opts)
+            | Request::Call(_, _, _, opts)
+            | Request::CallMany(_, _, opts)
+            | Request::Construct(_, _, _, opts)
+            | Request::StreamCall(_, _, opts)
+            | Request::StreamDemand(_, _, _, opts)
+            | Request::CallExport(_, _, _, _, opts)
+            | Request::CallHandle(_, _, _, opts)
+            | Request::GetProperty(_, _, _, opts)
+            | Request::GetGlobal(_, _, opts)
+            | Request::InvokeMethod(_, _, _, _, opts)
+            | Request::AwaitPromise(_, _, opts) => Some(opts),
+            _ => None,
+        }
+    }
 }
 
 pub enum Response {
     EnvCreated(EnvId),
+    EnvFailed(EnvId, Box<Response>),
     EnvDestroyed,
+    EnvReset,
     Result(JsResult),
+    Results(Vec<JsResult>),
+    Timeout,
+    OutOfMemory,
+    PoolCreated(PoolId),
+    PoolFailed(PoolId, Value),
+    CheckedOut(EnvId),
+    CheckedIn,
+    Canceled,
+    CancelRequested,
+    Panic(String),
+    EnvLost,
+    Released,
+    PromiseState(PromiseStatus),
+    StreamPaused,
+    StreamEnded,
 }
 
 // Detect TypeScript code by looking for type annotation patterns
@@ -79,246 +248,882 @@ fn transpile_typescript(code: &str, specifier: &str) -> Result<String, String> {
 }
 
 pub(crate) struct Engine {
+    // Declared first so handles are dropped before the isolate they live in
+    handles: Handles,
+    streams: Streams,
     runtime: JsRuntime,
+    interrupt: Arc<Interrupt>,
+    modules: Modules,
 }
 
-pub(crate) struct EngineManager {
-    engines: HashMap<EnvId, Engine>,
-    next_id: EnvId,
-}
+impl Engine {
+    pub fn new(config: &EnvConfig) -> Result<Self, String> {
+        let mut runtime = JsRuntime::new(runtime_options(config));
+        let interrupt = Arc::new(Interrupt::new(runtime.v8_isolate().thread_safe_handle()));
 
-impl EngineManager {
-    pub fn new() -> Self {
-        let mut manager = EngineManager {
-            engines: HashMap::new(),
-            next_id: 1, // 0 is reserved for default environment
+        // Without this, reaching the heap limit is a fatal error that aborts the
+        // whole BEAM node. Stop the script instead, and give V8 enough headroom
+        // to unwind it; the engine is replaced afterwards.
+        let oom_interrupt = interrupt.clone();
+        runtime.add_near_heap_limit_callback(move |current_limit, _initial_limit| {
+            oom_interrupt.fire(Termination::OutOfMemory);
+            current_limit * 2
+        });
+        let mut new_engine = Engine {
+            handles: Handles::default(),
+            streams: Streams::default(),
+            runtime,
+            interrupt,
+            modules: Modules::default(),
         };
-        // Create default environment
-        manager.engines.insert(0, Engine::new());
-        manager
+
+        // A snapshot already has runtime.js evaluated in it, and maybe modules
+        if config.snapshot.is_some() {
+            new_engine.modules = Modules::from_snapshot(&mut new_engine.runtime);
+            return Ok(new_engine);
+        }
+
+        new_engine
+            .runtime
+            .execute_script_static("[core:runtime]", include_str!("./runtime.js"))
+            .map_err(|e| format!("Failed to initialize JavaScript runtime: {:?}", e))?;
+
+        Ok(new_engine)
     }
 
     pub async fn handle(&mut self, req: &Request) -> Response {
-        match req {
-            Request::CreateEnv => {
-                let id = self.next_id;
-                self.next_id += 1;
-                self.engines.insert(id, Engine::new());
-                Response::EnvCreated(id)
+        let interrupt = self.interrupt.clone();
+        let fired = interrupt.fired();
+
+        // Timers started by this request are tied to it, so canceling the
+        // request drops them too
+        let scope = Rc::new(CancelHandle::new());
+        self.runtime.op_state().borrow_mut().put(scope.clone());
+
+        let timeout_ms = req.options().and_then(|opts| opts.timeout_ms);
+        let watchdog =
+            timeout_ms.map(|ms| Watchdog::start(interrupt.clone(), Duration::from_millis(ms)));
+
+        // A busy script is stopped by terminating the isolate, but a request
+        // parked on a pending promise has to be abandoned here instead
+        let response = tokio::select! {
+            response = self.execute(req) => response,
+            _ = fired => Response::Timeout,
+        };
+        drop(watchdog);
+
+        match interrupt.reset() {
+            Some(Termination::Timeout) => Response::Timeout,
+            Some(Termination::OutOfMemory) => Response::OutOfMemory,
+            Some(Termination::Canceled) => {
+                scope.cancel();
+                Response::Canceled
             }
-            Request::DestroyEnv(id) => {
-                if *id == 0 {
-                    Response::Result(Err(Value::String(
-                        "Cannot destroy default environment".to_string(),
-                    )))
-                } else if self.engines.remove(id).is_some() {
-                    Response::EnvDestroyed
-                } else {
-                    Response::Result(Err(Value::String(format!("Environment {} not found", id))))
-                }
+            None => response,
+        }
+    }
+
+    pub fn interrupt(&self) -> Arc<Interrupt> {
+        self.interrupt.clone()
+    }
+
+    async fn execute(&mut self, req: &Request) -> Response {
+        let value = match req {
+            Request::Load(_, files, _) => return Response::Result(self.load(files).await),
+            Request::Run(_, code, bindings, _) if bindings.names.is_empty() => {
+                execute_script(&mut self.runtime, code)
             }
-            Request::Load(env_id, files) => {
-                if let Some(engine) = self.engines.get_mut(env_id) {
-                    Response::Result(engine.load(files).await)
-                } else {
-                    Response::Result(Err(Value::String(format!(
-                        "Environment {} not found",
-                        env_id
-                    ))))
-                }
+            Request::Run(_, code, bindings, _) => {
+                execute_with_bindings(&mut self.runtime, code, bindings)
             }
-            Request::Run(env_id, code) => {
-                if let Some(engine) = self.engines.get_mut(env_id) {
-                    Response::Result(engine.run(code).await)
-                } else {
-                    Response::Result(Err(Value::String(format!(
-                        "Environment {} not found",
-                        env_id
-                    ))))
-                }
+            Request::Call(_, path, args, _) => call_internal(&mut self.runtime, None, path, args),
+            Request::CallMany(_, calls, opts) => return self.call_many(calls, opts).await,
+            Request::Construct(_, path, args, _) => {
+                construct_internal(&mut self.runtime, path, args)
             }
-            Request::Call(env_id, fn_name, args) => {
-                if let Some(engine) = self.engines.get_mut(env_id) {
-                    Response::Result(engine.call(fn_name, args).await)
-                } else {
-                    Response::Result(Err(Value::String(format!(
-                        "Environment {} not found",
-                        env_id
-                    ))))
-                }
+            Request::CallExport(_, specifier, path, args, _) => {
+                self.call_export(specifier, path, args)
+            }
+            Request::CallHandle(_, id, args, _) => self.call_handle(*id, args),
+            Request::GetProperty(_, id, path, _) => self.get_property(Some(*id), path),
+            Request::SetProperty(_, id, path, value) => {
+                let result = self.set_property(Some(*id), path, value);
+                return Response::Result(result.map(|_| Exported::nil()));
+            }
+            Request::GetGlobal(_, path, _) => self.get_property(None, path),
+            Request::SetGlobal(_, path, value) => {
+                let result = self.set_property(None, path, value);
+                return Response::Result(result.map(|_| Exported::nil()));
+            }
+            Request::InvokeMethod(_, id, path, args, _) => match self.handles.get(*id).cloned() {
+                Ok(root) => call_internal(&mut self.runtime, Some(root), path, args),
+                Err(err) => Err(err),
+            },
+            Request::StreamCall(_, start, opts) => return self.stream_call(start, opts).await,
+            Request::StreamDemand(_, id, demand, _) => {
+                return self.stream_demand(*id, *demand).await
+            }
+            Request::StreamClose(_, id) => {
+                self.close_stream(*id);
+                return Response::Released;
             }
+            Request::AwaitPromise(_, id, _) => self.handles.get(*id).cloned(),
+            Request::PromiseState(_, id) => {
+                return match self.promise_state(*id).await {
+                    Ok(status) => Response::PromiseState(status),
+                    Err(err) => Response::Result(Err(err)),
+                };
+            }
+            Request::Release(_, id) => {
+                self.handles.release(*id);
+                return Response::Released;
+            }
+            // Environment and pool lifecycle is handled by the `EngineManager`
+            _ => Err(Value::String(
+                "Environment lifecycle requests cannot be handled by an engine".to_string(),
+            )),
+        };
+
+        let opts = req.options().cloned().unwrap_or_default();
+        Response::Result(self.finish(value, &opts).await)
+    }
+
+    // Runs every call in turn, each with its own result, so one failing call
+    // doesn't stop the rest
+    async fn call_many(&mut self, calls: &[(Vec<String>, Terms)], opts: &ExecOptions) -> Response {
+        let mut results = Vec::with_capacity(calls.len());
+        for (path, args) in calls {
+            let value = call_internal(&mut self.runtime, None, path, args);
+            results.push(self.finish(value, opts).await);
         }
+        Response::Results(results)
     }
-}
 
-impl Engine {
-    pub fn new() -> Self {
-        let mut new_engine = Engine {
-            runtime: JsRuntime::new(RuntimeOptions {
-                module_loader: Some(Rc::new(FsModuleLoader)),
-                extensions: vec![Extension {
-                    name: "core:apis",
-                    ops: std::borrow::Cow::Borrowed(&[op_set_timeout::DECL]),
-                    ..Default::default()
-                }],
-                ..Default::default()
-            }),
+    // Waits for a promise result unless asked not to, then exports it
+    async fn finish(
+        &mut self,
+        value: Result<v8::Global<v8::Value>, Value>,
+        opts: &ExecOptions,
+    ) -> JsResult {
+        let value = match value {
+            Ok(value) if opts.awaits => self
+                .runtime
+                .resolve_value(value)
+                .await
+                .map_err(|err| anyhow_error_to_json(&err)),
+            Ok(value) => match self.detach(value) {
+                Ok(promise) => return Ok(promise),
+                Err(value) => Ok(value),
+            },
+            Err(err) => Err(err),
+        };
+        value.and_then(|value| self.export(value, opts))
+    }
+
+    async fn load(&mut self, js_files: &[String]) -> JsResult {
+        load_files(&mut self.runtime, js_files, &mut self.modules).await
+    }
+
+    fn call_export(
+        &mut self,
+        specifier: &str,
+        path: &[String],
+        args: &Terms,
+    ) -> Result<v8::Global<v8::Value>, Value> {
+        // Accept a plain file path as well as a URL
+        let module_specifier = match ModuleSpecifier::parse(specifier) {
+            Ok(module_specifier) => module_specifier,
+            Err(_) => file_specifier(specifier)?,
         };
-        // This should never fail as runtime.js is embedded at compile time
-        if let Err(e) = new_engine
+        let mod_id = self.modules.ids.get(&module_specifier).ok_or_else(|| {
+            Value::String(format!("Module {} has not been loaded", module_specifier))
+        })?;
+        let namespace = self
             .runtime
-            .execute_script_static("[core:runtime]", include_str!("./runtime.js"))
-        {
-            panic!("Failed to initialize JavaScript runtime: {:?}", e);
-        }
+            .get_module_namespace(*mod_id)
+            .map_err(|e| anyhow_error_to_json(&e))?;
+        let namespace = {
+            let scope = &mut self.runtime.handle_scope();
+            let local = v8::Local::new(scope, namespace);
+            v8::Global::new(scope, v8::Local::<v8::Value>::from(local))
+        };
+        call_internal(&mut self.runtime, Some(namespace), path, args)
+    }
 
-        new_engine
+    fn call_handle(&mut self, id: HandleId, args: &Terms) -> Result<v8::Global<v8::Value>, Value> {
+        let func = self.handles.get(id)?.clone();
+        let scope = &mut self.runtime.handle_scope();
+        let func = v8::Local::new(scope, func);
+        let receiver = v8::undefined(scope).into();
+        call_function(scope, receiver, func, &format!("Handle {}", id), args)
     }
 
-    async fn run(&mut self, code: &str) -> JsResult {
-        // Transpile TypeScript to JavaScript if needed
-        let js_code = transpile_typescript(code, "[inline]").map_err(Value::String)?;
+    // Reads `path` from a handle's value, or from the global object without one
+    fn get_property(
+        &mut self,
+        id: Option<HandleId>,
+        path: &[String],
+    ) -> Result<v8::Global<v8::Value>, Value> {
+        let scope = &mut self.runtime.handle_scope();
+        let root = lookup_root(scope, &self.handles, id)?;
+        let (_, value) = resolve_path(scope, root, path)?;
+        Ok(v8::Global::new(scope, value))
+    }
+
+    fn set_property(
+        &mut self,
+        id: Option<HandleId>,
+        path: &[String],
+        value: &Terms,
+    ) -> Result<(), Value> {
+        let scope = &mut self.runtime.handle_scope();
+        let root = lookup_root(scope, &self.handles, id)?;
+        let (parent, _) = resolve_path(scope, root, path)?;
 
-        let result = eval_raw(&mut self.runtime, &js_code).await.map(|val| {
+        let name = path.join(".");
+        let parent = parent
+            .to_object(scope)
+            .ok_or_else(|| Value::String(format!("Cannot set {} on a non-object", name)))?;
+        let key = v8::String::new(scope, &path[path.len() - 1])
+            .ok_or_else(|| Value::String(format!("Error creating V8 string from {}", name)))?;
+        let value = match value.to_v8(scope)?.pop() {
+            Some(value) => value,
+            None => v8::undefined(scope).into(),
+        };
+        match parent.set(scope, key.into(), value) {
+            Some(true) => Ok(()),
+            _ => Err(Value::String(format!("Failed to set {}", name))),
+        }
+    }
+
+    // Calls a function and starts streaming the iterator it returns
+    async fn stream_call(&mut self, start: &StreamStart, opts: &ExecOptions) -> Response {
+        let value = match call_internal(&mut self.runtime, None, &start.path, &start.args) {
+            Ok(value) => self
+                .runtime
+                .resolve_value(value)
+                .await
+                .map_err(|err| anyhow_error_to_json(&err)),
+            Err(err) => Err(err),
+        };
+        let opened = value.and_then(|value| {
             let scope = &mut self.runtime.handle_scope();
-            let local = v8::Local::new(scope, val);
-            serde_v8::from_v8::<Value>(scope, local)
+            let value = v8::Local::new(scope, value);
+            open_iterator(scope, value, &start.path.join("."))
         });
 
-        match result {
-            Ok(Ok(value)) => Ok(value),
-            Ok(Err(err)) => Err(serde_v8_error_to_json(&err)),
-            Err(err) => Err(anyhow_error_to_json(&err)),
+        match opened {
+            Ok((iterator, next)) => {
+                let stream = Stream {
+                    iterator,
+                    next,
+                    sink: start.sink.clone(),
+                    credit: start.demand,
+                    opts: opts.clone(),
+                };
+                self.streams.insert(start.id, stream);
+                self.pump(start.id).await
+            }
+            Err(err) => Response::Result(Err(err)),
         }
     }
 
-    async fn load(&mut self, js_files: &[String]) -> JsResult {
-        for file_path in js_files {
-            // Read the file contents
-            let contents = std::fs::read_to_string(file_path).map_err(|e| {
-                Value::String(format!("Failed to read file '{}': {}", file_path, e))
-            })?;
-
-            // Determine if this is TypeScript
-            let is_typescript = file_path.ends_with(".ts") || file_path.ends_with(".tsx");
-
-            // Transpile TypeScript if needed
-            let js_code = if is_typescript {
-                transpile_typescript(&contents, file_path).map_err(Value::String)?
-            } else {
-                contents.clone()
+    async fn stream_demand(&mut self, id: StreamId, demand: usize) -> Response {
+        match self.streams.get_mut(id) {
+            Ok(stream) => stream.credit = stream.credit.saturating_add(demand),
+            Err(err) => return Response::Result(Err(err)),
+        }
+        self.pump(id).await
+    }
+
+    // Sends values until the stream runs out of credit or the iterator is done.
+    // A stream that ends or fails is dropped.
+    async fn pump(&mut self, id: StreamId) -> Response {
+        loop {
+            let (iterator, next, opts) = match self.streams.get_mut(id) {
+                Ok(stream) if stream.credit == 0 => return Response::StreamPaused,
+                Ok(stream) => (
+                    stream.iterator.clone(),
+                    stream.next.clone(),
+                    stream.opts.clone(),
+                ),
+                Err(err) => return Response::Result(Err(err)),
             };
 
-            // Determine if this is an ES module (contains import/export statements)
-            let is_module = js_code.contains("import ") || js_code.contains("export ");
+            let value = match self.step(iterator, next).await {
+                Ok(Some(value)) => self.export(value, &opts),
+                Ok(None) => {
+                    self.streams.remove(id);
+                    return Response::StreamEnded;
+                }
+                Err(err) => Err(err),
+            };
+            match (value, self.streams.get_mut(id)) {
+                (Ok(value), Ok(stream)) => {
+                    stream.credit -= 1;
+                    (stream.sink)(value);
+                }
+                (Err(err), _) | (_, Err(err)) => {
+                    self.streams.remove(id);
+                    return Response::Result(Err(err));
+                }
+            }
+        }
+    }
 
-            if is_module {
-                // Handle as ES module
-                let absolute_path = std::fs::canonicalize(file_path).map_err(|e| {
-                    Value::String(format!("Failed to resolve path {}: {}", file_path, e))
-                })?;
+    // Advances an iterator once, returning `None` when it is done
+    async fn step(
+        &mut self,
+        iterator: v8::Global<v8::Object>,
+        next: v8::Global<v8::Function>,
+    ) -> Result<Option<v8::Global<v8::Value>>, Value> {
+        let result = {
+            let scope = &mut self.runtime.handle_scope();
+            let iterator = v8::Local::new(scope, iterator);
+            let next = v8::Local::new(scope, next);
+            let result = next
+                .call(scope, iterator.into(), &[])
+                .ok_or_else(|| Value::String("Error advancing iterator".to_string()))?;
+            v8::Global::new(scope, result)
+        };
 
-                let module_specifier =
-                    ModuleSpecifier::from_file_path(&absolute_path).map_err(|_| {
-                        Value::String(format!(
-                            "Failed to create module specifier from path: {}",
-                            absolute_path.display()
-                        ))
-                    })?;
+        // Async iterators hand back a promise for every step
+        let result = self
+            .runtime
+            .resolve_value(result)
+            .await
+            .map_err(|err| anyhow_error_to_json(&err))?;
 
-                let module_code = ModuleCode::from(FastString::from(js_code));
+        let scope = &mut self.runtime.handle_scope();
+        let result = v8::Local::new(scope, result);
+        let result = result
+            .to_object(scope)
+            .ok_or_else(|| Value::String("Iterator result is not an object".to_string()))?;
+        let done = get_key(scope, result, "done")?;
+        if done.boolean_value(scope) {
+            return Ok(None);
+        }
+        let value = get_key(scope, result, "value")?;
+        Ok(Some(v8::Global::new(scope, value)))
+    }
 
-                // Load the module
-                let mod_id = self
-                    .runtime
-                    .load_main_module(&module_specifier, Some(module_code))
-                    .await
-                    .map_err(|e| Value::String(format!("Failed to load module: {}", e)))?;
+    // Stops a stream early, giving the iterator a chance to clean up
+    fn close_stream(&mut self, id: StreamId) {
+        let Some(stream) = self.streams.remove(id) else {
+            return;
+        };
+        let scope = &mut self.runtime.handle_scope();
+        let iterator = v8::Local::new(scope, stream.iterator);
+        if let Ok(cleanup) = get_key(scope, iterator, "return") {
+            if let Ok(cleanup) = v8::Local::<v8::Function>::try_from(cleanup) {
+                cleanup.call(scope, iterator.into(), &[]);
+            }
+        }
+    }
 
-                // Evaluate the module
-                let result = self.runtime.mod_evaluate(mod_id);
-                self.runtime
-                    .run_event_loop(Default::default())
-                    .await
-                    .map_err(|e| Value::String(format!("Failed to evaluate module: {}", e)))?;
+    // Keeps a promise pending as a handle, tagged so Elixir can await it later.
+    // Any other value is given back to be exported as usual.
+    fn detach(&mut self, value: v8::Global<v8::Value>) -> Result<Exported, v8::Global<v8::Value>> {
+        let scope = &mut self.runtime.handle_scope();
+        let local = v8::Local::new(scope, &value);
+        let Ok(promise) = v8::Local::<v8::Promise>::try_from(local) else {
+            return Err(value);
+        };
 
-                // Wait for the module evaluation to complete
-                let _ = result
-                    .await
-                    .map_err(|e| Value::String(format!("Module evaluation error: {}", e)))?;
-            } else {
-                // Handle as regular script (not a module)
-                self.run(&js_code).await?;
-            }
+        // A rejection nobody is listening for yet would otherwise be reported
+        // as an uncaught error by whatever request next runs the event loop
+        let ignore = v8::Function::new(
+            scope,
+            |_: &mut v8::HandleScope, _: v8::FunctionCallbackArguments, _: v8::ReturnValue| {},
+        );
+        if let Some(ignore) = ignore {
+            promise.catch(scope, ignore);
         }
-        Ok(Value::Null)
-    }
-
-    async fn call(&mut self, fn_name: &str, args: &[Value]) -> JsResult {
-        call_internal(&mut self.runtime, fn_name, args).await
-    }
-}
-
-pub async fn call_internal(js_runtime: &mut JsRuntime, fn_name: &str, args: &[Value]) -> JsResult {
-    let call_result = {
-        let scope = &mut js_runtime.handle_scope();
-        let context = scope.get_current_context();
-        let global = context.global(scope);
-
-        let fn_key = v8::String::new(scope, fn_name)
-            .ok_or_else(|| Value::String(format!("Error creating V8 string from {}", fn_name)))?;
-        let func = global
-            .get(scope, fn_key.into())
-            .ok_or_else(|| Value::String(format!("Function {} not found", fn_name)))?;
-        let func = v8::Local::<v8::Function>::try_from(func)
-            .map_err(|_| Value::String(format!("{} is not a callable function", fn_name)))?;
-
-        let v8_args: Result<Vec<_>, _> = args
-            .iter()
-            .map(|arg| {
-                serde_v8::to_v8(scope, arg)
-                    .map_err(|_| Value::String("Error converting argument to V8 value".to_string()))
-            })
-            .collect();
-
-        match v8_args {
-            Ok(v8_args) => func
-                .call(scope, global.into(), &v8_args)
-                .map(|local| v8::Global::new(scope, local))
-                .ok_or_else(|| Value::String(format!("Error calling function {}", fn_name))),
-            Err(e) => Err(e),
+        Ok(Exported::handle(
+            "promise",
+            self.handles.insert(scope, local),
+        ))
+    }
+
+    async fn promise_state(&mut self, id: HandleId) -> Result<PromiseStatus, Value> {
+        let promise = self.handles.get(id)?.clone();
+
+        // Give work that finished since the last request, like expired timers,
+        // a chance to settle the promise, without waiting on anything
+        let runtime = &mut self.runtime;
+        if let Poll::Ready(Err(err)) =
+            poll_fn(|cx| Poll::Ready(runtime.poll_event_loop(cx, false))).await
+        {
+            return Err(anyhow_error_to_json(&err));
         }
-    };
 
-    match call_result {
-        Ok(result) => js_runtime
-            .resolve_value(result)
-            .await
-            .map(|result| {
-                let scope = &mut js_runtime.handle_scope();
-                let local = v8::Local::new(scope, result);
-                serde_v8::from_v8::<Value>(scope, local).map_err(|err| serde_v8_error_to_json(&err))
-            })
-            .map_err(|err| anyhow_error_to_json(&err))?,
-        Err(err) => Err(err),
+        let scope = &mut self.runtime.handle_scope();
+        let promise = v8::Local::new(scope, promise);
+        let promise = v8::Local::<v8::Promise>::try_from(promise)
+            .map_err(|_| Value::String(format!("Handle {} is not a promise", id)))?;
+        Ok(match promise.state() {
+            v8::PromiseState::Pending => PromiseStatus::Pending,
+            v8::PromiseState::Fulfilled => PromiseStatus::Fulfilled,
+            v8::PromiseState::Rejected => PromiseStatus::Rejected,
+        })
+    }
+
+    // Converts a result for Elixir. Functions in it, or the whole result when a
+    // handle was asked for, stay behind in the engine as handles.
+    fn export(&mut self, value: v8::Global<v8::Value>, opts: &ExecOptions) -> JsResult {
+        let scope = &mut self.runtime.handle_scope();
+        let local = v8::Local::new(scope, value);
+        match opts.returns {
+            Returns::Value => v8_to_term(scope, local, &mut self.handles, &opts.structs),
+            Returns::Handle => Ok(Exported::handle(
+                "object",
+                self.handles.insert(scope, local),
+            )),
+        }
     }
 }
 
-async fn eval_raw(
+pub async fn run_script(
     js_runtime: &mut JsRuntime,
     code: &str,
-) -> Result<v8::Global<v8::Value>, anyhow::Error> {
-    let module: ModuleCode = FastString::from(code.to_owned());
-    let result = js_runtime.execute_script("[core]", module);
+) -> Result<v8::Global<v8::Value>, Value> {
+    let value = execute_script(js_runtime, code)?;
+    js_runtime
+        .resolve_value(value)
+        .await
+        .map_err(|err| anyhow_error_to_json(&err))
+}
+
+// Like `run_script`, but a resulting promise is left for the caller to resolve
+fn execute_script(js_runtime: &mut JsRuntime, code: &str) -> Result<v8::Global<v8::Value>, Value> {
+    // Transpile TypeScript to JavaScript if needed
+    let js_code = transpile_typescript(code, "[inline]").map_err(Value::String)?;
+
+    let module: ModuleCode = FastString::from(js_code);
+    js_runtime
+        .execute_script("[core]", module)
+        .map_err(|err| anyhow_error_to_json(&err))
+}
+
+// Evaluates `code` inside a function whose parameters are the bindings, so they
+// are local variables for this evaluation only. Direct eval sees them, and the
+// code itself is passed as an argument rather than spliced into the source.
+fn execute_with_bindings(
+    js_runtime: &mut JsRuntime,
+    code: &str,
+    bindings: &Bindings,
+) -> Result<v8::Global<v8::Value>, Value> {
+    let js_code = transpile_typescript(code, "[inline]").map_err(Value::String)?;
 
-    match result {
-        Ok(value) => js_runtime.resolve_value(value).await,
-        Err(err) => Err(err),
+    let names = &bindings.names;
+    if let Some(name) = names.iter().find(|name| !is_binding_name(name)) {
+        return Err(Value::String(format!(
+            "Invalid binding name {:?}: must be a JavaScript identifier",
+            name
+        )));
     }
+    let wrapper = format!(
+        "(function ({}) {{ return eval(arguments[{}]); }})",
+        names.join(", "),
+        names.len()
+    );
+    let func = js_runtime
+        .execute_script("[bindings]", FastString::from(wrapper))
+        .map_err(|err| anyhow_error_to_json(&err))?;
+
+    let scope = &mut js_runtime.handle_scope();
+    let func = v8::Local::new(scope, func);
+    let func = v8::Local::<v8::Function>::try_from(func)
+        .map_err(|_| Value::String("Error creating bindings wrapper".to_string()))?;
+    let mut args = bindings.values.to_v8(scope)?;
+    let code = v8::String::new(scope, &js_code)
+        .ok_or_else(|| Value::String("Error creating V8 string from code".to_string()))?;
+    args.push(code.into());
+
+    // Errors are the evaluated code's own, so report their message
+    let scope = &mut v8::TryCatch::new(scope);
+    let receiver = v8::undefined(scope).into();
+    match func.call(scope, receiver, &args) {
+        Some(result) => Ok(v8::Global::new(scope, result)),
+        None => Err(Value::String(match scope.exception() {
+            Some(exception) => exception.to_rust_string_lossy(scope),
+            None => "Error evaluating code with bindings".to_string(),
+        })),
+    }
+}
+
+// Plain identifiers only, which also keeps anything else out of the wrapper's
+// source. `eval` and `arguments` would break the wrapper itself.
+fn is_binding_name(name: &str) -> bool {
+    const RESERVED: &str = "arguments await break case catch class const continue debugger \
+        default delete do else enum eval export extends false finally for function if \
+        implements import in instanceof interface let new null package private protected \
+        public return static super switch this throw true try typeof var void while with yield";
+
+    let mut chars = name.chars();
+    let starts_well =
+        matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '$');
+    starts_well
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+        && !RESERVED.split_whitespace().any(|word| word == name)
+}
+
+// The ES modules loaded into a runtime, by specifier. A runtime has room for a
+// single main module, which one booted from a snapshot may already hold.
+#[derive(Default)]
+pub struct Modules {
+    ids: HashMap<ModuleSpecifier, ModuleId>,
+    has_main: bool,
+}
+
+// Where a snapshot lists the modules in it, for `Modules::from_snapshot`
+const SNAPSHOT_MODULES_KEY: &str = "__jsengine_modules__";
+
+impl Modules {
+    // Finds the modules a snapshot was created with. They are already
+    // registered, so loading them again only looks up their ids.
+    fn from_snapshot(js_runtime: &mut JsRuntime) -> Self {
+        let script = format!("globalThis.{} ?? []", SNAPSHOT_MODULES_KEY);
+        let specifiers = match js_runtime.execute_script("[jsengine:modules]", script.into()) {
+            Ok(specifiers) => {
+                let scope = &mut js_runtime.handle_scope();
+                let specifiers = v8::Local::new(scope, specifiers);
+                serde_v8::from_v8::<Vec<String>>(scope, specifiers).unwrap_or_default()
+            }
+            Err(_) => Vec::new(),
+        };
+
+        let mut ids = HashMap::new();
+        for specifier in specifiers {
+            let Ok(specifier) = ModuleSpecifier::parse(&specifier) else {
+                continue;
+            };
+            let loaded = js_runtime.load_side_module(&specifier, None);
+            if let Ok(id) = futures::executor::block_on(loaded) {
+                ids.insert(specifier, id);
+            }
+        }
+        // Any of them may be the main module, and side modules work either way
+        Modules {
+            ids,
+            has_main: true,
+        }
+    }
+
+    // Lists the modules in a runtime about to be snapshotted
+    fn record(&self, js_runtime: &mut JsRuntime) -> Result<(), Value> {
+        let specifiers = self.ids.keys().map(|specifier| specifier.as_str());
+        let specifiers = serde_json::to_string(&specifiers.collect::<Vec<_>>())
+            .map_err(|e| Value::String(e.to_string()))?;
+        let script = format!(
+            "Object.defineProperty(globalThis, '{}', {{ value: {} }});",
+            SNAPSHOT_MODULES_KEY, specifiers
+        );
+        js_runtime
+            .execute_script("[jsengine:modules]", script.into())
+            .map(|_| ())
+            .map_err(|e| anyhow_error_to_json(&e))
+    }
+}
+
+pub async fn load_files(
+    js_runtime: &mut JsRuntime,
+    js_files: &[String],
+    modules: &mut Modules,
+) -> JsResult {
+    for file_path in js_files {
+        // Read the file contents
+        let contents = std::fs::read_to_string(file_path)
+            .map_err(|e| Value::String(format!("Failed to read file '{}': {}", file_path, e)))?;
+
+        // Determine if this is TypeScript
+        let is_typescript = file_path.ends_with(".ts") || file_path.ends_with(".tsx");
+
+        // Transpile TypeScript if needed
+        let js_code = if is_typescript {
+            transpile_typescript(&contents, file_path).map_err(Value::String)?
+        } else {
+            contents.clone()
+        };
+
+        // Determine if this is an ES module (contains import/export statements)
+        let is_module = js_code.contains("import ") || js_code.contains("export ");
+
+        if is_module {
+            // Handle as ES module
+            let module_specifier = file_specifier(file_path)?;
+
+            // ES modules are only ever evaluated once
+            if modules.ids.contains_key(&module_specifier) {
+                continue;
+            }
+
+            let module_code = ModuleCode::from(FastString::from(js_code));
+
+            // Load the module; a runtime only has room for one main module
+            let loaded = if !modules.has_main {
+                modules.has_main = true;
+                js_runtime
+                    .load_main_module(&module_specifier, Some(module_code))
+                    .await
+            } else {
+                js_runtime
+                    .load_side_module(&module_specifier, Some(module_code))
+                    .await
+            };
+            let mod_id =
+                loaded.map_err(|e| Value::String(format!("Failed to load module: {}", e)))?;
+
+            // Evaluate the module
+            let result = js_runtime.mod_evaluate(mod_id);
+            js_runtime
+                .run_event_loop(Default::default())
+                .await
+                .map_err(|e| Value::String(format!("Failed to evaluate module: {}", e)))?;
+
+            // Wait for the module evaluation to complete
+            let _ = result
+                .await
+                .map_err(|e| Value::String(format!("Module evaluation error: {}", e)))?;
+            modules.ids.insert(module_specifier, mod_id);
+        } else {
+            // Handle as regular script (not a module)
+            run_script(js_runtime, &js_code).await?;
+        }
+    }
+    Ok(Exported::nil())
+}
+
+// Calls the function at `path`, looked up from `root` or else the global object.
+// A returned promise is left for the caller to resolve.
+pub fn call_internal(
+    js_runtime: &mut JsRuntime,
+    root: Option<v8::Global<v8::Value>>,
+    path: &[String],
+    args: &Terms,
+) -> Result<v8::Global<v8::Value>, Value> {
+    let scope = &mut js_runtime.handle_scope();
+    let root = match root {
+        Some(root) => v8::Local::new(scope, root),
+        None => scope.get_current_context().global(scope).into(),
+    };
+    let (receiver, func) = resolve_path(scope, root, path)?;
+    call_function(scope, receiver, func, &path.join("."), args)
+}
+
+// Calls the class or constructor function at `path` with `new`, looked up from
+// the global object like `call_internal`
+pub fn construct_internal(
+    js_runtime: &mut JsRuntime,
+    path: &[String],
+    args: &Terms,
+) -> Result<v8::Global<v8::Value>, Value> {
+    let scope = &mut js_runtime.handle_scope();
+    let global = scope.get_current_context().global(scope).into();
+    let (_, constructor) = resolve_path(scope, global, path)?;
+
+    let name = path.join(".");
+    let constructor = v8::Local::<v8::Function>::try_from(constructor)
+        .map_err(|_| Value::String(format!("{} is not a constructor", name)))?;
+
+    let v8_args = args.to_v8(scope)?;
+    constructor
+        .new_instance(scope, &v8_args)
+        .map(|instance| v8::Global::new(scope, v8::Local::<v8::Value>::from(instance)))
+        .ok_or_else(|| Value::String(format!("Error constructing {}", name)))
+}
+
+// Calls `func` with `this` set to `receiver`. A returned promise is left for
+// the caller to resolve.
+fn call_function(
+    scope: &mut v8::HandleScope,
+    receiver: v8::Local<v8::Value>,
+    func: v8::Local<v8::Value>,
+    fn_name: &str,
+    args: &Terms,
+) -> Result<v8::Global<v8::Value>, Value> {
+    let func = v8::Local::<v8::Function>::try_from(func)
+        .map_err(|_| Value::String(format!("{} is not a callable function", fn_name)))?;
+
+    let v8_args = args.to_v8(scope)?;
+    func.call(scope, receiver, &v8_args)
+        .map(|local| v8::Global::new(scope, local))
+        .ok_or_else(|| Value::String(format!("Error calling function {}", fn_name)))
+}
+
+// Finds the iterator to stream in a function's result: from its async iterator
+// method, else its sync one, else the result itself if it has a `next` method
+fn open_iterator(
+    scope: &mut v8::HandleScope,
+    value: v8::Local<v8::Value>,
+    name: &str,
+) -> Result<(v8::Global<v8::Object>, v8::Global<v8::Function>), Value> {
+    let not_iterable = || Value::String(format!("{} did not return an iterable", name));
+    let object = match value.is_null_or_undefined() {
+        true => None,
+        false => value.to_object(scope),
+    }
+    .ok_or_else(not_iterable)?;
+
+    let mut iterator = object;
+    let keys = [
+        v8::Symbol::get_async_iterator(scope),
+        v8::Symbol::get_iterator(scope),
+    ];
+    for key in keys {
+        let method = object.get(scope, key.into());
+        if let Some(Ok(method)) = method.map(v8::Local::<v8::Function>::try_from) {
+            let opened = method.call(scope, object.into(), &[]);
+            iterator = opened
+                .and_then(|opened| opened.to_object(scope))
+                .ok_or_else(not_iterable)?;
+            break;
+        }
+    }
+
+    let next = get_key(scope, iterator, "next")?;
+    let next = v8::Local::<v8::Function>::try_from(next).map_err(|_| not_iterable())?;
+    Ok((
+        v8::Global::new(scope, iterator),
+        v8::Global::new(scope, next),
+    ))
+}
+
+fn get_key<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    object: v8::Local<v8::Object>,
+    key: &str,
+) -> Result<v8::Local<'s, v8::Value>, Value> {
+    let name = v8::String::new(scope, key)
+        .ok_or_else(|| Value::String(format!("Error creating V8 string from {}", key)))?;
+    object
+        .get(scope, name.into())
+        .ok_or_else(|| Value::String(format!("Error reading {}", key)))
+}
+
+// The value paths start from: a handle's value, or else the global object
+fn lookup_root<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    handles: &Handles,
+    id: Option<HandleId>,
+) -> Result<v8::Local<'s, v8::Value>, Value> {
+    match id {
+        Some(id) => Ok(v8::Local::new(scope, handles.get(id)?)),
+        None => Ok(scope.get_current_context().global(scope).into()),
+    }
+}
+
+// Walks a path like ["MyLib", "utils", "format"] from `root`, returning the
+// value found along with the object it was read from, so it can be called as
+// a method
+fn resolve_path<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    root: v8::Local<'s, v8::Value>,
+    path: &[String],
+) -> Result<(v8::Local<'s, v8::Value>, v8::Local<'s, v8::Value>), Value> {
+    if path.is_empty() {
+        return Err(Value::String("Function path cannot be empty".to_string()));
+    }
+
+    let mut receiver = root;
+    let mut current = root;
+    for (depth, segment) in path.iter().enumerate() {
+        let walked = path[..depth].join(".");
+        let object = match current.is_null_or_undefined() {
+            true => None,
+            false => current.to_object(scope),
+        }
+        .ok_or_else(|| Value::String(format!("{} is {}", walked, current.type_repr())))?;
+
+        let key = v8::String::new(scope, segment)
+            .ok_or_else(|| Value::String(format!("Error creating V8 string from {}", segment)))?;
+        receiver = current;
+        current = object.get(scope, key.into()).ok_or_else(|| {
+            Value::String(format!("Function {} not found", path[..=depth].join(".")))
+        })?;
+    }
+
+    Ok((receiver, current))
+}
+
+fn file_specifier(file_path: &str) -> Result<ModuleSpecifier, Value> {
+    let absolute_path = std::fs::canonicalize(file_path)
+        .map_err(|e| Value::String(format!("Failed to resolve path {}: {}", file_path, e)))?;
+
+    ModuleSpecifier::from_file_path(&absolute_path).map_err(|_| {
+        Value::String(format!(
+            "Failed to create module specifier from path: {}",
+            absolute_path.display()
+        ))
+    })
+}
+
+fn runtime_options(config: &EnvConfig) -> RuntimeOptions {
+    let options = &config.options;
+    let create_params = options.max_heap_size.map(|max| {
+        v8::CreateParams::default().heap_limits(options.initial_heap_size.unwrap_or(0), max)
+    });
+
+    RuntimeOptions {
+        module_loader: Some(Rc::new(FsModuleLoader)),
+        extensions: vec![Extension {
+            name: "core:apis",
+            ops: std::borrow::Cow::Borrowed(&[op_set_timeout::DECL]),
+            ..Default::default()
+        }],
+        startup_snapshot: config
+            .snapshot
+            .as_ref()
+            .map(|bytes| Snapshot::Boxed(bytes.to_vec().into_boxed_slice())),
+        create_params,
+        ..Default::default()
+    }
+}
+
+// Writes a startup snapshot with runtime.js and `js_files` already evaluated,
+// for `EnvOptions::snapshot`. The snapshot runtime gets a thread of its own,
+// like every other runtime.
+pub fn create_snapshot(js_files: Vec<String>, out_path: String) -> JsResult {
+    thread::spawn(move || build_snapshot(&js_files, &out_path))
+        .join()
+        .unwrap_or_else(|_| Err(Value::String("Snapshot creation panicked".to_string())))
+}
+
+fn build_snapshot(js_files: &[String], out_path: &str) -> JsResult {
+    // Initialize V8 for regular use first. Whichever runtime comes first sets
+    // the process-wide flags, and snapshotting would make every later isolate
+    // run with --predictable and a fixed random seed.
+    JsRuntime::init_platform(None);
+
+    let tokio_runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| Value::String(format!("Failed to create Tokio runtime: {}", e)))?;
+    let mut runtime = JsRuntimeForSnapshot::new(runtime_options(&EnvConfig::default()));
+
+    runtime
+        .execute_script_static("[core:runtime]", include_str!("./runtime.js"))
+        .map_err(|e| anyhow_error_to_json(&e))?;
+    let mut modules = Modules::default();
+    tokio_runtime.block_on(load_files(&mut runtime, js_files, &mut modules))?;
+    modules.record(&mut runtime)?;
+
+    let snapshot = runtime.snapshot();
+    std::fs::write(out_path, &*snapshot)
+        .map_err(|e| Value::String(format!("Failed to write snapshot '{}': {}", out_path, e)))?;
+    Ok(Exported::nil())
 }
 
+// Resolves to false if the request that set the timer was canceled first
 #[op2(async)]
-async fn op_set_timeout(#[serde] delay: u64) -> Result<(), AnyError> {
-    tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
-    Ok(())
+async fn op_set_timeout(state: Rc<RefCell<OpState>>, #[serde] delay: u64) -> bool {
+    let scope = state
+        .borrow()
+        .try_borrow::<Rc<CancelHandle>>()
+        .cloned()
+        .unwrap_or_default();
+    tokio::time::sleep(std::time::Duration::from_millis(delay))
+        .or_cancel(scope)
+        .await
+        .is_ok()
 }
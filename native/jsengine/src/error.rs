@@ -4,6 +4,7 @@ use serde::{de, ser};
 use std::fmt::Display;
 
 quick_error! {
+    #[allow(clippy::enum_variant_names)]
     #[derive(Debug)]
     pub enum Error {
         DeserializationError(err: String) {
@@ -0,0 +1,37 @@
+use deno_core::serde_json::Value;
+use deno_core::v8;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub(crate) type HandleId = u64;
+
+// Shared by every engine, so a handle left over from an engine that has since
+// been replaced can never pick up an unrelated value
+static NEXT_HANDLE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// JavaScript values kept alive on behalf of Elixir until they are released.
+#[derive(Default)]
+pub(crate) struct Handles {
+    values: HashMap<HandleId, v8::Global<v8::Value>>,
+}
+
+impl Handles {
+    pub fn insert(&mut self, scope: &mut v8::HandleScope, value: v8::Local<v8::Value>) -> HandleId {
+        let id = NEXT_HANDLE_ID.fetch_add(1, Ordering::Relaxed);
+        self.values.insert(id, v8::Global::new(scope, value));
+        id
+    }
+
+    pub fn get(&self, id: HandleId) -> Result<&v8::Global<v8::Value>, Value> {
+        self.values.get(&id).ok_or_else(|| {
+            Value::String(format!(
+                "Handle {} is no longer valid; it was released or its environment was reset",
+                id
+            ))
+        })
+    }
+
+    pub fn release(&mut self, id: HandleId) {
+        self.values.remove(&id);
+    }
+}
@@ -0,0 +1,94 @@
+use deno_core::v8;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Why JavaScript execution in an environment was stopped from the outside.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Termination {
+    Timeout,
+    OutOfMemory,
+    Canceled,
+}
+
+/// Stops whatever an engine is currently doing, from any thread.
+///
+/// Terminating the isolate only interrupts JavaScript that is actually running,
+/// so waiters are also woken to abandon requests parked on a pending promise.
+pub(crate) struct Interrupt {
+    isolate: v8::IsolateHandle,
+    reason: Mutex<Option<Termination>>,
+    notify: Notify,
+}
+
+impl Interrupt {
+    pub fn new(isolate: v8::IsolateHandle) -> Self {
+        Interrupt {
+            isolate,
+            reason: Mutex::new(None),
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn fire(&self, reason: Termination) {
+        if let Ok(mut current) = self.reason.lock() {
+            // The first reason wins if several fire for the same request
+            current.get_or_insert(reason);
+        }
+        self.isolate.terminate_execution();
+        self.notify.notify_waiters();
+    }
+
+    /// Resolves once `fire` is called. Must be created before the work it guards
+    /// starts so no wakeup can slip in between.
+    pub fn fired(&self) -> tokio::sync::futures::Notified<'_> {
+        self.notify.notified()
+    }
+
+    /// Clears a termination, if any, so the isolate can run JavaScript again.
+    pub fn reset(&self) -> Option<Termination> {
+        let reason = self
+            .reason
+            .lock()
+            .ok()
+            .and_then(|mut current| current.take());
+        if reason.is_some() {
+            self.isolate.cancel_terminate_execution();
+        }
+        reason
+    }
+}
+
+/// Fires a `Termination::Timeout` if it isn't dropped before the deadline.
+pub(crate) struct Watchdog {
+    disarm: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    pub fn start(interrupt: Arc<Interrupt>, timeout: Duration) -> Self {
+        let (disarm, disarmed) = channel::<()>();
+        let thread = thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = disarmed.recv_timeout(timeout) {
+                interrupt.fire(Termination::Timeout);
+            }
+        });
+
+        Watchdog {
+            disarm: Some(disarm),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        // Disconnecting wakes the thread; joining guarantees it can't fire late
+        drop(self.disarm.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
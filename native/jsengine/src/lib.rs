@@ -3,69 +3,247 @@ mod atoms;
 mod conv;
 mod engine;
 mod error;
+mod handles;
+mod interrupt;
+mod manager;
+mod streams;
 
-use crate::conv::{json_to_term, term_to_json};
-use crate::engine::Request::{Call, CreateEnv, DestroyEnv, Load, Run};
-use crate::engine::{EngineManager, EnvId, Request, Response};
+use crate::conv::{decode_tagged, json_to_term, term_to_string, Exported, StructLookup, Terms};
+use crate::engine::Request::{
+    AwaitPromise, Call, CallExport, CallHandle, CallMany, Cancel, Checkin, Checkout, Construct,
+    CreateEnv, CreatePool, DestroyEnv, GetGlobal, GetProperty, InvokeMethod, Load, PromiseState,
+    Release, ResetEnv, Run, SetGlobal, SetProperty, StreamCall, StreamClose, StreamDemand,
+};
+use crate::engine::{
+    Bindings, EnvConfig, EnvId, EnvOptions, ExecOptions, Fault, PoolId, Request, RequestId,
+    Response,
+};
+use crate::handles::HandleId;
+use crate::manager::{EngineManager, Reply};
+use crate::streams::{StreamId, StreamStart};
 
-use deno_core::serde_json::Value;
-use rustler::{Encoder, Env, Error, NifResult, Term};
+use rustler::env::OwnedEnv;
+use rustler::types::MapIterator;
+use rustler::{Decoder, Encoder, Env, Error, LocalPid, NifResult, NifStruct, ResourceArc, Term};
 
 use once_cell::sync::Lazy;
-use std::sync::mpsc::{channel, Sender};
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 
-// Register NIFs: create_env/0, destroy_env/1, load_env/2, run_env/2, call_env/3
+// Register NIFs: create_env_with_options/1, destroy_env/1, reset_env/1, load_env/3, run_env/4,
+// call_env/4, call_many_env/3, construct_env/4, get_global_env/3, set_global_env/3,
+// call_export_env/5, call_handle_env/3, the handle API get_handle_property/3,
+// set_handle_property/3, invoke_handle_method/4, release_handle/1, the promise API
+// await_promise_env/2, promise_state_env/1, the streaming API stream_call_env/6,
+// stream_ack_env/2, stream_close_env/1, plus the non-blocking load_env_async/3, run_env_async/4,
+// call_env_async/4, cancel/1, the pool API create_pool_with_options/2, checkout/1,
+// checkin/2, create_snapshot/2, struct_resolved/2, and inject_fault/2 for tests
 rustler::init!(
     "Elixir.JSEngine",
-    [create_env, destroy_env, load_env, run_env, call_env],
+    [
+        create_env_with_options,
+        destroy_env,
+        reset_env,
+        load_env,
+        run_env,
+        call_env,
+        call_many_env,
+        construct_env,
+        get_global_env,
+        set_global_env,
+        call_export_env,
+        call_handle_env,
+        get_handle_property,
+        set_handle_property,
+        invoke_handle_method,
+        release_handle,
+        await_promise_env,
+        promise_state_env,
+        stream_call_env,
+        stream_ack_env,
+        stream_close_env,
+        load_env_async,
+        run_env_async,
+        call_env_async,
+        cancel,
+        create_pool_with_options,
+        checkout,
+        checkin,
+        create_snapshot,
+        struct_resolved,
+        inject_fault
+    ],
     load = init
 );
 
-type ChannelSender = Arc<Mutex<Sender<(Request, Sender<Response>)>>>;
+static ENGINE_MANAGER: Lazy<Mutex<EngineManager>> = Lazy::new(|| Mutex::new(EngineManager::new()));
 
-static GLOBAL_CHANNEL: Lazy<ChannelSender> = Lazy::new(|| {
-    let (sender, receiver) = channel::<(Request, Sender<Response>)>();
-    let sender = Arc::new(Mutex::new(sender));
-    let runtime = tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()
-        .expect("Failed to create Tokio runtime - this should never fail");
+// A panic while the manager was locked must not shut every NIF out for good.
+// Each environment's state lives on its own thread, so the manager's
+// bookkeeping is still usable afterwards.
+fn manager() -> MutexGuard<'static, EngineManager> {
+    ENGINE_MANAGER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+// Identifies an async request; its result is sent back tagged with this
+// reference. It names its environment without keeping it alive.
+pub struct RequestRef {
+    env_id: EnvId,
+    id: RequestId,
+}
 
-    /* Spawn the master thread */
-    thread::spawn(move || {
-        let mut engine_manager = EngineManager::new();
+// Handle to a created environment. Ids never leave Rust, so an environment can
+// only be reached by whoever holds its handle, and it is destroyed once the
+// last handle is garbage collected.
+pub struct EnvResource {
+    id: EnvId,
+}
 
-        for (request, response_sender) in receiver {
-            let async_result = engine_manager.handle(&request);
-            let result = runtime.block_on(async_result);
-            let _ = response_sender.send(result);
+impl Drop for EnvResource {
+    fn drop(&mut self) {
+        manager().release_env(self.id);
+    }
+}
+
+// Handle to a pool of pre-warmed environments, released when garbage collected
+pub struct PoolResource {
+    id: PoolId,
+}
+
+impl Drop for PoolResource {
+    fn drop(&mut self) {
+        manager().release_pool(self.id);
+    }
+}
+
+// An environment as passed in from Elixir: the default one or a handle. Values
+// returned from an environment hold on to this, so it outlives them.
+#[derive(Clone)]
+pub struct EnvRef {
+    id: EnvId,
+    resource: Option<ResourceArc<EnvResource>>,
+}
+
+impl Encoder for EnvRef {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match &self.resource {
+            Some(resource) => resource.encode(env),
+            None => atoms::default().encode(env),
         }
-    });
+    }
+}
 
-    sender
-});
+impl<'a> Decoder<'a> for EnvRef {
+    fn decode(term: Term<'a>) -> NifResult<Self> {
+        if term.is_atom() && atoms::default().eq(&term) {
+            return Ok(EnvRef {
+                id: 0,
+                resource: None,
+            });
+        }
 
-fn init(_env: Env, _term: rustler::Term) -> bool {
-    true
+        // Otherwise it must be a handle returned by create_env
+        let resource = term.decode::<ResourceArc<EnvResource>>()?;
+        Ok(EnvRef {
+            id: resource.id,
+            resource: Some(resource),
+        })
+    }
 }
 
-// Helper function to extract environment ID from term (supports atom :default or integer)
-fn extract_env_id<'a>(_env: Env<'a>, term: Term<'a>) -> Result<EnvId, Error> {
-    // Try to decode as atom first (for :default)
-    if term.is_atom() && atoms::default().eq(&term) {
-        return Ok(0);
+// A JavaScript value kept alive in its environment on behalf of Elixir,
+// released from the environment when garbage collected
+pub struct HandleResource {
+    env_id: EnvId,
+    id: HandleId,
+}
+
+impl Drop for HandleResource {
+    fn drop(&mut self) {
+        let release = Request::Release(self.env_id, self.id);
+        let _ = manager().dispatch(release, Reply::ignore());
     }
+}
 
-    // Try to decode as integer (for environment references)
-    term.decode::<u64>()
+// A stream started by `stream_call_env`. It holds the options every later
+// batch runs with, and closes the stream when garbage collected.
+pub struct StreamRef {
+    env: EnvRef,
+    id: StreamId,
+    opts: ExecOptions,
+    // The reference its messages are tagged with, in the external term format
+    tag: Arc<Vec<u8>>,
+}
+
+impl Drop for StreamRef {
+    fn drop(&mut self) {
+        let close = Request::StreamClose(self.env.id, self.id);
+        let _ = manager().dispatch(close, Reply::ignore());
+    }
+}
+
+#[derive(NifStruct)]
+#[module = "JSEngine.Function"]
+pub struct Function {
+    env: EnvRef,
+    handle: ResourceArc<HandleResource>,
+}
+
+#[derive(NifStruct)]
+#[module = "JSEngine.Handle"]
+pub struct ObjectHandle {
+    env: EnvRef,
+    handle: ResourceArc<HandleResource>,
+}
+
+#[derive(NifStruct)]
+#[module = "JSEngine.Promise"]
+pub struct PromiseHandle {
+    env: EnvRef,
+    handle: ResourceArc<HandleResource>,
+}
+
+// rustler's `resource!` expands to an `impl` inside this function
+#[allow(non_local_definitions)]
+fn init(env: Env, _term: rustler::Term) -> bool {
+    rustler::resource!(RequestRef, env);
+    rustler::resource!(EnvResource, env);
+    rustler::resource!(PoolResource, env);
+    rustler::resource!(HandleResource, env);
+    rustler::resource!(StreamRef, env);
+    rustler::resource!(StructLookup, env);
+    true
+}
+
+// Helper function to extract environment ID from term (supports atom :default or an env handle)
+fn extract_env_id<'a>(env: Env<'a>, term: Term<'a>) -> Result<EnvId, Error> {
+    extract_env(env, term).map(|env_ref| env_ref.id)
+}
+
+fn extract_env<'a>(_env: Env<'a>, term: Term<'a>) -> Result<EnvRef, Error> {
+    term.decode::<EnvRef>()
         .map_err(|_| Error::Atom("invalid_env_id"))
 }
 
 #[rustler::nif(schedule = "DirtyCpu")]
-fn create_env<'a>(env: Env<'a>) -> NifResult<Term<'a>> {
-    send_msg_raw(env, CreateEnv)
+fn create_env_with_options<'a>(env: Env<'a>, opts: EnvOptions) -> NifResult<Term<'a>> {
+    let config = match EnvConfig::new(opts) {
+        Ok(config) => config,
+        Err(err) => return Ok((atoms::error(), json_to_term(env, &err)).encode(env)),
+    };
+    match send_msg(CreateEnv(config))? {
+        Response::EnvFailed(id, response) => {
+            // No handle was handed out, so nothing else will release it
+            manager().release_env(id);
+            Ok(encode_response(env, *response))
+        }
+        response => Ok(encode_response(env, response)),
+    }
 }
 
 #[rustler::nif(schedule = "DirtyCpu")]
@@ -74,51 +252,624 @@ fn destroy_env<'a>(env: Env<'a>, env_id_term: Term<'a>) -> NifResult<Term<'a>> {
     send_msg_raw(env, DestroyEnv(env_id))
 }
 
+// Replaces the environment's engine, keeping its handle, and runs its preload
+// list again. Also works on the default environment.
 #[rustler::nif(schedule = "DirtyCpu")]
-fn load_env<'a>(env: Env<'a>, env_id_term: Term<'a>, js_files: Vec<String>) -> NifResult<Term<'a>> {
+fn reset_env<'a>(env: Env<'a>, env_id_term: Term<'a>) -> NifResult<Term<'a>> {
     let env_id = extract_env_id(env, env_id_term)?;
-    send_msg_raw(env, Load(env_id, js_files))
+    send_msg_raw(env, ResetEnv(env_id))
 }
 
 #[rustler::nif(schedule = "DirtyCpu")]
-fn run_env<'a>(env: Env<'a>, env_id_term: Term<'a>, code: String) -> NifResult<Term<'a>> {
-    let env_id = extract_env_id(env, env_id_term)?;
-    send_msg_raw(env, Run(env_id, code))
+fn load_env<'a>(
+    env: Env<'a>,
+    env_id_term: Term<'a>,
+    js_files: Vec<String>,
+    opts: ExecOptions,
+) -> NifResult<Term<'a>> {
+    let owner = extract_env(env, env_id_term)?;
+    send_env_msg(env, &owner, Load(owner.id, js_files, opts))
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn run_env<'a>(
+    env: Env<'a>,
+    env_id_term: Term<'a>,
+    code: String,
+    bindings: Term<'a>,
+    opts: ExecOptions,
+) -> NifResult<Term<'a>> {
+    let owner = extract_env(env, env_id_term)?;
+    let bindings = extract_bindings(bindings, &opts)?;
+    send_env_msg(env, &owner, Run(owner.id, code, bindings, opts))
 }
 
 #[rustler::nif(schedule = "DirtyCpu")]
 fn call_env<'a>(
     env: Env<'a>,
     env_id_term: Term<'a>,
-    fn_name: String,
+    path: Term<'a>,
     args: Vec<Term<'a>>,
+    opts: ExecOptions,
 ) -> NifResult<Term<'a>> {
+    let owner = extract_env(env, env_id_term)?;
+    let path = extract_path(path)?;
+    let arg_vals = extract_args(args, &opts);
+    send_env_msg(env, &owner, Call(owner.id, path, arg_vals, opts))
+}
+
+// Makes a batch of calls in one round trip to the environment
+#[rustler::nif(schedule = "DirtyCpu")]
+fn call_many_env<'a>(
+    env: Env<'a>,
+    env_id_term: Term<'a>,
+    calls: Vec<(Term<'a>, Vec<Term<'a>>)>,
+    opts: ExecOptions,
+) -> NifResult<Term<'a>> {
+    let owner = extract_env(env, env_id_term)?;
+    let calls = calls
+        .into_iter()
+        .map(|(path, args)| Ok((extract_path(path)?, extract_args(args, &opts))))
+        .collect::<Result<Vec<_>, Error>>()?;
+    send_env_msg(env, &owner, CallMany(owner.id, calls, opts))
+}
+
+// Creates an instance of the class at `path`, as with `new`
+#[rustler::nif(schedule = "DirtyCpu")]
+fn construct_env<'a>(
+    env: Env<'a>,
+    env_id_term: Term<'a>,
+    path: Term<'a>,
+    args: Vec<Term<'a>>,
+    opts: ExecOptions,
+) -> NifResult<Term<'a>> {
+    let owner = extract_env(env, env_id_term)?;
+    let path = extract_path(path)?;
+    let arg_vals = extract_args(args, &opts);
+    send_env_msg(env, &owner, Construct(owner.id, path, arg_vals, opts))
+}
+
+// Reads a global variable, or a nested property of one by path
+#[rustler::nif(schedule = "DirtyCpu")]
+fn get_global_env<'a>(
+    env: Env<'a>,
+    env_id_term: Term<'a>,
+    name: Term<'a>,
+    opts: ExecOptions,
+) -> NifResult<Term<'a>> {
+    let owner = extract_env(env, env_id_term)?;
+    let path = extract_path(name)?;
+    send_env_msg(env, &owner, GetGlobal(owner.id, path, opts))
+}
+
+// Writes a term straight to a global variable, without evaluating any code
+#[rustler::nif(schedule = "DirtyCpu")]
+fn set_global_env<'a>(
+    env: Env<'a>,
+    env_id_term: Term<'a>,
+    name: Term<'a>,
+    value: Term<'a>,
+) -> NifResult<Term<'a>> {
+    let owner = extract_env(env, env_id_term)?;
+    let path = extract_path(name)?;
+    let value = Terms::new([value], &ExecOptions::default());
+    send_env_msg(env, &owner, SetGlobal(owner.id, path, value))
+}
+
+// Calls an export of an ES module loaded in the environment, by its specifier
+#[rustler::nif(schedule = "DirtyCpu")]
+fn call_export_env<'a>(
+    env: Env<'a>,
+    env_id_term: Term<'a>,
+    specifier: String,
+    path: Term<'a>,
+    args: Vec<Term<'a>>,
+    opts: ExecOptions,
+) -> NifResult<Term<'a>> {
+    let owner = extract_env(env, env_id_term)?;
+    let path = extract_path(path)?;
+    let arg_vals = extract_args(args, &opts);
+    send_env_msg(
+        env,
+        &owner,
+        CallExport(owner.id, specifier, path, arg_vals, opts),
+    )
+}
+
+// Calls a function previously returned as a `%JSEngine.Function{}`
+#[rustler::nif(schedule = "DirtyCpu")]
+fn call_handle_env<'a>(
+    env: Env<'a>,
+    fun: Function,
+    args: Vec<Term<'a>>,
+    opts: ExecOptions,
+) -> NifResult<Term<'a>> {
+    let arg_vals = extract_args(args, &opts);
+    let request = CallHandle(fun.env.id, fun.handle.id, arg_vals, opts);
+    send_env_msg(env, &fun.env, request)
+}
+
+// Reads a property of a handle's value, or a nested one by path
+#[rustler::nif(schedule = "DirtyCpu")]
+fn get_handle_property<'a>(
+    env: Env<'a>,
+    handle: Term<'a>,
+    key: Term<'a>,
+    opts: ExecOptions,
+) -> NifResult<Term<'a>> {
+    let (owner, handle) = extract_handle(handle)?;
+    let path = extract_path(key)?;
+    send_env_msg(env, &owner, GetProperty(owner.id, handle.id, path, opts))
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn set_handle_property<'a>(
+    env: Env<'a>,
+    handle: Term<'a>,
+    key: Term<'a>,
+    value: Term<'a>,
+) -> NifResult<Term<'a>> {
+    let (owner, handle) = extract_handle(handle)?;
+    let path = extract_path(key)?;
+    let value = Terms::new([value], &ExecOptions::default());
+    send_env_msg(env, &owner, SetProperty(owner.id, handle.id, path, value))
+}
+
+// Calls a method on a handle's value, with the value as `this`
+#[rustler::nif(schedule = "DirtyCpu")]
+fn invoke_handle_method<'a>(
+    env: Env<'a>,
+    handle: Term<'a>,
+    method: Term<'a>,
+    args: Vec<Term<'a>>,
+    opts: ExecOptions,
+) -> NifResult<Term<'a>> {
+    let (owner, handle) = extract_handle(handle)?;
+    let path = extract_path(method)?;
+    let arg_vals = extract_args(args, &opts);
+    let request = InvokeMethod(owner.id, handle.id, path, arg_vals, opts);
+    send_env_msg(env, &owner, request)
+}
+
+// Frees a handle's value right away instead of waiting for garbage collection.
+// It is queued without waiting, as the environment may be busy running a script.
+#[rustler::nif]
+fn release_handle<'a>(env: Env<'a>, handle: Term<'a>) -> NifResult<Term<'a>> {
+    let (owner, handle) = extract_handle(handle)?;
+    let inline_response = manager().dispatch(Release(owner.id, handle.id), Reply::ignore());
+
+    match inline_response {
+        Some(response) => Ok(encode_response(env, response)),
+        None => Ok(atoms::ok().encode(env)),
+    }
+}
+
+// Waits for a promise returned with `await: false` to settle
+#[rustler::nif(schedule = "DirtyCpu")]
+fn await_promise_env<'a>(
+    env: Env<'a>,
+    promise: PromiseHandle,
+    opts: ExecOptions,
+) -> NifResult<Term<'a>> {
+    let request = AwaitPromise(promise.env.id, promise.handle.id, opts);
+    send_env_msg(env, &promise.env, request)
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn promise_state_env<'a>(env: Env<'a>, promise: PromiseHandle) -> NifResult<Term<'a>> {
+    send_msg_raw(env, PromiseState(promise.env.id, promise.handle.id))
+}
+
+// Calls a function that returns an iterable and streams what it yields to the
+// pid in `to`, a {pid, ref} pair, as {:jsengine, ref, {:next, value}}, `demand`
+// values at a time (nil for no limit), then {:jsengine, ref, :done} or
+// {:jsengine, ref, {:error, reason}}. `ref` is a plain reference made by the
+// caller: the engine keeps the sink, so tagging messages with the returned
+// stream would keep it from ever being garbage collected.
+#[rustler::nif]
+fn stream_call_env<'a>(
+    env: Env<'a>,
+    env_id_term: Term<'a>,
+    path: Term<'a>,
+    args: Vec<Term<'a>>,
+    (to, tag): (LocalPid, Term<'a>),
+    demand: Option<usize>,
+    opts: ExecOptions,
+) -> NifResult<Term<'a>> {
+    let owner = extract_env(env, env_id_term)?;
+    let path = extract_path(path)?;
+    let arg_vals = extract_args(args, &opts);
+    let stream_ref = ResourceArc::new(StreamRef {
+        env: owner.clone(),
+        id: NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+        opts: opts.clone(),
+        tag: Arc::new(tag.to_binary().as_slice().to_vec()),
+    });
+
+    let (sink_owner, sink_tag) = (owner.clone(), stream_ref.tag.clone());
+    let start = StreamStart {
+        id: stream_ref.id,
+        path,
+        args: arg_vals,
+        demand: demand.unwrap_or(usize::MAX),
+        sink: Arc::new(move |value| {
+            send_stream_event(&to, &sink_tag, |env| {
+                let value = encode_value(env, &sink_owner, &value);
+                (atoms::next(), value).encode(env)
+            });
+        }),
+    };
+    let reply = stream_reply(to, &stream_ref);
+
+    // Bound first so the lock is released before `stream_ref` can be dropped,
+    // as dropping it closes the stream through the manager
+    let inline_response = manager().dispatch(StreamCall(owner.id, start, opts), reply);
+
+    match inline_response {
+        Some(response) => Ok(encode_response(env, response)),
+        None => Ok((atoms::ok(), stream_ref).encode(env)),
+    }
+}
+
+// Lets a stream send `demand` more values to the calling process
+#[rustler::nif]
+fn stream_ack_env<'a>(
+    env: Env<'a>,
+    stream: ResourceArc<StreamRef>,
+    demand: usize,
+) -> NifResult<Term<'a>> {
+    let request = StreamDemand(stream.env.id, stream.id, demand, stream.opts.clone());
+    let inline_response = manager().dispatch(request, stream_reply(env.pid(), &stream));
+
+    match inline_response {
+        Some(response) => Ok(encode_response(env, response)),
+        None => Ok(atoms::ok().encode(env)),
+    }
+}
+
+// Stops a stream before it is done
+#[rustler::nif]
+fn stream_close_env<'a>(env: Env<'a>, stream: ResourceArc<StreamRef>) -> NifResult<Term<'a>> {
+    let _ = manager().dispatch(StreamClose(stream.env.id, stream.id), Reply::ignore());
+    Ok(atoms::ok().encode(env))
+}
+
+// Reports how a batch of a stream finished: nothing if it is only waiting for
+// more demand, otherwise the end of the stream or its error
+fn stream_reply(pid: LocalPid, stream: &StreamRef) -> Reply {
+    let (owner, tag) = (stream.env.clone(), stream.tag.clone());
+    Reply::new(move |response| {
+        if let Response::StreamPaused = response {
+            return;
+        }
+        send_stream_event(&pid, &tag, |env| encode_result(env, &owner, response));
+    })
+}
+
+// Sends {:jsengine, ref, event}, with `ref` decoded from a stream's tag
+fn send_stream_event<F>(pid: &LocalPid, tag: &[u8], event: F)
+where
+    F: for<'a> FnOnce(Env<'a>) -> Term<'a>,
+{
+    let mut msg_env = OwnedEnv::new();
+    let _ = msg_env.send_and_clear(pid, |env| {
+        let tag = env
+            .binary_to_term(tag)
+            .map_or_else(|| atoms::nil().encode(env), |(tag, _)| tag);
+        (atoms::jsengine(), tag, event(env)).encode(env)
+    });
+}
+
+#[rustler::nif]
+fn load_env_async<'a>(
+    env: Env<'a>,
+    env_id_term: Term<'a>,
+    js_files: Vec<String>,
+    opts: ExecOptions,
+) -> NifResult<Term<'a>> {
+    let owner = extract_env(env, env_id_term)?;
+    send_msg_async(env, &owner, Load(owner.id, js_files, opts))
+}
+
+#[rustler::nif]
+fn run_env_async<'a>(
+    env: Env<'a>,
+    env_id_term: Term<'a>,
+    code: String,
+    bindings: Term<'a>,
+    opts: ExecOptions,
+) -> NifResult<Term<'a>> {
+    let owner = extract_env(env, env_id_term)?;
+    let bindings = extract_bindings(bindings, &opts)?;
+    send_msg_async(env, &owner, Run(owner.id, code, bindings, opts))
+}
+
+#[rustler::nif]
+fn call_env_async<'a>(
+    env: Env<'a>,
+    env_id_term: Term<'a>,
+    path: Term<'a>,
+    args: Vec<Term<'a>>,
+    opts: ExecOptions,
+) -> NifResult<Term<'a>> {
+    let owner = extract_env(env, env_id_term)?;
+    let path = extract_path(path)?;
+    let arg_vals = extract_args(args, &opts);
+    send_msg_async(env, &owner, Call(owner.id, path, arg_vals, opts))
+}
+
+// Stops an async request, whether it is still queued or already running; it
+// then replies with {:error, :canceled}. Finished requests are unaffected.
+#[rustler::nif]
+fn cancel(env: Env, request: ResourceArc<RequestRef>) -> NifResult<Term> {
+    send_msg_raw(env, Cancel(request.env_id, request.id))
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn create_pool_with_options(env: Env, size: usize, opts: EnvOptions) -> NifResult<Term> {
+    let config = match EnvConfig::new(opts) {
+        Ok(config) => config,
+        Err(err) => return Ok((atoms::error(), json_to_term(env, &err)).encode(env)),
+    };
+    match send_msg(CreatePool(size, config))? {
+        Response::PoolFailed(pool_id, err) => {
+            // No handle was handed out, so nothing else will release it
+            manager().release_pool(pool_id);
+            Ok((atoms::error(), json_to_term(env, &err)).encode(env))
+        }
+        response => Ok(encode_response(env, response)),
+    }
+}
+
+// Waits for the environment to finish preloading, so runs on a dirty scheduler
+#[rustler::nif(schedule = "DirtyCpu")]
+fn checkout(env: Env, pool: ResourceArc<PoolResource>) -> NifResult<Term> {
+    match send_msg(Checkout(pool.id))? {
+        Response::EnvFailed(id, response) => {
+            // Its preload failed, so it is retired like a checked in one
+            manager().release_env(id);
+            Ok(encode_response(env, *response))
+        }
+        response => Ok(encode_response(env, response)),
+    }
+}
+
+#[rustler::nif]
+fn checkin<'a>(
+    env: Env<'a>,
+    pool: ResourceArc<PoolResource>,
+    env_id_term: Term<'a>,
+) -> NifResult<Term<'a>> {
+    let env_id = extract_env_id(env, env_id_term)?;
+    send_msg_raw(env, Checkin(pool.id, env_id))
+}
+
+// Snapshots are built on a runtime of their own, not in any environment
+#[rustler::nif(schedule = "DirtyCpu")]
+fn create_snapshot(env: Env, js_files: Vec<String>, out_path: String) -> NifResult<Term> {
+    Ok(encode_response(
+        env,
+        Response::Result(engine::create_snapshot(js_files, out_path)),
+    ))
+}
+
+// Answers a lookup of the struct resolver with a module's default struct, or
+// nil if the module isn't loaded or doesn't define one
+#[rustler::nif]
+fn struct_resolved<'a>(
+    env: Env<'a>,
+    lookup: ResourceArc<StructLookup>,
+    default: Term<'a>,
+) -> NifResult<Term<'a>> {
+    let default = match default.is_map() {
+        true => Some(default.to_binary().as_slice().to_vec()),
+        false => None,
+    };
+    lookup.answer(default);
+    Ok(atoms::ok().encode(env))
+}
+
+// Makes an environment's thread panic, or exit as if it had died, so tests can
+// check how failures are handled. Raises unless the NIF was built with the
+// `fault-injection` feature, as it is for tests.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn inject_fault<'a>(env: Env<'a>, env_id_term: Term<'a>, fault: Fault) -> NifResult<Term<'a>> {
     let env_id = extract_env_id(env, env_id_term)?;
-    let json_args: Result<Vec<Value>, _> =
-        args.into_iter().map(|arg| term_to_json(env, arg)).collect();
 
-    match json_args {
-        Ok(arg_vals) => send_msg_raw(env, Call(env_id, fn_name, arg_vals)),
-        Err(_err) => Err(Error::Atom("invalid_type")),
+    #[cfg(feature = "fault-injection")]
+    return send_msg_raw(env, Request::InjectFault(env_id, fault));
+
+    #[cfg(not(feature = "fault-injection"))]
+    {
+        let _ = (env_id, fault);
+        Err(Error::RaiseAtom("fault_injection_disabled"))
+    }
+}
+
+// Accepts "a.b.c" or ["a", "b", "c"]
+fn extract_path(path: Term) -> Result<Vec<String>, Error> {
+    match path.decode::<String>() {
+        Ok(name) => Ok(name.split('.').map(String::from).collect()),
+        Err(_) => path
+            .decode::<Vec<String>>()
+            .map_err(|_| Error::Atom("invalid_path")),
+    }
+}
+
+// Accepts a map of values keyed by name, as strings or atoms
+fn extract_bindings(bindings: Term, opts: &ExecOptions) -> Result<Bindings, Error> {
+    let entries = bindings
+        .decode::<MapIterator>()
+        .map_err(|_| Error::Atom("invalid_bindings"))?;
+    let (names, values): (Vec<_>, Vec<_>) = entries
+        .map(|(name, value)| {
+            let name = match name.decode::<String>() {
+                Ok(name) => name,
+                Err(_) => term_to_string(&name).map_err(|_| Error::Atom("invalid_bindings"))?,
+            };
+            Ok((name, value))
+        })
+        .collect::<Result<Vec<_>, Error>>()?
+        .into_iter()
+        .unzip();
+    Ok(Bindings {
+        names,
+        values: Terms::new(values, opts),
+    })
+}
+
+// Accepts either kind of handle: a `%JSEngine.Handle{}` or a `%JSEngine.Function{}`
+fn extract_handle(term: Term) -> Result<(EnvRef, ResourceArc<HandleResource>), Error> {
+    if let Ok(handle) = term.decode::<ObjectHandle>() {
+        return Ok((handle.env, handle.handle));
     }
+    term.decode::<Function>()
+        .map(|fun| (fun.env, fun.handle))
+        .map_err(|_| Error::Atom("invalid_handle"))
+}
+
+// Arguments are converted to JavaScript on the engine thread, straight from
+// the terms
+fn extract_args(args: Vec<Term>, opts: &ExecOptions) -> Terms {
+    Terms::new(args, opts)
 }
 
 fn send_msg_raw<'a>(env: Env<'a>, msg: Request) -> NifResult<Term<'a>> {
+    send_msg(msg).map(|response| encode_response(env, response))
+}
+
+// For requests whose results can hold handles into the environment
+fn send_env_msg<'a>(env: Env<'a>, owner: &EnvRef, msg: Request) -> NifResult<Term<'a>> {
+    send_msg(msg).map(|response| encode_result(env, owner, response))
+}
+
+fn send_msg(msg: Request) -> Result<Response, Error> {
     let (sender, receiver) = channel::<Response>();
-    let global_sender = GLOBAL_CHANNEL
-        .lock()
-        .map_err(|_| Error::Atom("mutex_poisoned"))?;
 
-    global_sender
-        .send((msg, sender))
-        .map_err(|_| Error::Atom("sender_error"))?;
+    // Only hold the lock while routing, so environments don't wait on each other
+    let inline_response = manager().dispatch(msg, Reply::channel(sender));
+
+    match inline_response {
+        Some(response) => Ok(response),
+        // The reply was dropped unsent, which only happens if its thread died
+        None => Ok(receiver.recv().unwrap_or(Response::EnvLost)),
+    }
+}
+
+// Returns `{:ok, ref}` immediately; the result is later sent to the calling
+// process as `{:jsengine, ref, result}`, so no scheduler is held while JS runs.
+fn send_msg_async<'a>(env: Env<'a>, owner: &EnvRef, msg: Request) -> NifResult<Term<'a>> {
+    let pid = env.pid();
+    let owner = owner.clone();
+    let request_ref = ResourceArc::new(RequestRef {
+        env_id: owner.id,
+        id: NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+    });
+    let reply_ref = request_ref.clone();
+
+    let reply = Reply::tracked(request_ref.id, move |response| {
+        let mut msg_env = OwnedEnv::new();
+        let _ = msg_env.send_and_clear(&pid, |env| {
+            (
+                atoms::jsengine(),
+                reply_ref,
+                encode_result(env, &owner, response),
+            )
+                .encode(env)
+        });
+    });
+
+    let inline_response = manager().dispatch(msg, reply);
+
+    match inline_response {
+        Some(response) => Ok(encode_response(env, response)),
+        None => Ok((atoms::ok(), request_ref).encode(env)),
+    }
+}
+
+// Like `encode_response`, but values kept alive in `owner` come out as handles
+fn encode_result<'a>(env: Env<'a>, owner: &EnvRef, response: Response) -> Term<'a> {
+    match response {
+        Response::Result(Ok(val)) => (atoms::ok(), encode_value(env, owner, &val)).encode(env),
+        Response::Result(Err(err)) => (atoms::error(), json_to_term(env, &err)).encode(env),
+        Response::Results(results) => {
+            let results = results
+                .into_iter()
+                .map(|result| encode_result(env, owner, Response::Result(result)))
+                .collect::<Vec<_>>();
+            (atoms::ok(), results).encode(env)
+        }
+        response => encode_response(env, response),
+    }
+}
 
-    let response = receiver.recv().map_err(|_| Error::Atom("receiver_error"))?;
+fn encode_value<'a>(env: Env<'a>, owner: &EnvRef, value: &Exported) -> Term<'a> {
+    let term = value.load(env);
+    if !value.is_tagged() {
+        return term;
+    }
+    decode_tagged(env, term, &mut |env, kind, id| {
+        let handle = ResourceArc::new(HandleResource {
+            env_id: owner.id,
+            id,
+        });
+        match kind {
+            "function" => Some(
+                Function {
+                    env: owner.clone(),
+                    handle,
+                }
+                .encode(env),
+            ),
+            "promise" => Some(
+                PromiseHandle {
+                    env: owner.clone(),
+                    handle,
+                }
+                .encode(env),
+            ),
+            "object" => Some(
+                ObjectHandle {
+                    env: owner.clone(),
+                    handle,
+                }
+                .encode(env),
+            ),
+            _ => None,
+        }
+    })
+}
 
+fn encode_response(env: Env, response: Response) -> Term {
     match response {
-        Response::EnvCreated(id) => Ok((atoms::ok(), id).encode(env)),
-        Response::EnvDestroyed => Ok(atoms::ok().encode(env)),
-        Response::Result(Ok(val)) => Ok((atoms::ok(), json_to_term(env, &val)).encode(env)),
-        Response::Result(Err(err)) => Ok((atoms::error(), json_to_term(env, &err)).encode(env)),
+        Response::EnvCreated(id) => (atoms::ok(), ResourceArc::new(EnvResource { id })).encode(env),
+        Response::EnvFailed(_, response) => encode_response(env, *response),
+        Response::EnvDestroyed => atoms::ok().encode(env),
+        Response::EnvReset => atoms::ok().encode(env),
+        Response::Result(Ok(val)) => (atoms::ok(), val.load(env)).encode(env),
+        Response::Result(Err(err)) => (atoms::error(), json_to_term(env, &err)).encode(env),
+        Response::Results(results) => {
+            let results = results
+                .into_iter()
+                .map(|result| encode_response(env, Response::Result(result)))
+                .collect::<Vec<_>>();
+            (atoms::ok(), results).encode(env)
+        }
+        Response::Timeout => (atoms::error(), atoms::timeout()).encode(env),
+        Response::OutOfMemory => (atoms::error(), atoms::out_of_memory()).encode(env),
+        Response::PoolCreated(id) => {
+            (atoms::ok(), ResourceArc::new(PoolResource { id })).encode(env)
+        }
+        Response::PoolFailed(_, err) => (atoms::error(), json_to_term(env, &err)).encode(env),
+        Response::CheckedOut(id) => (atoms::ok(), ResourceArc::new(EnvResource { id })).encode(env),
+        Response::CheckedIn => atoms::ok().encode(env),
+        Response::Canceled => (atoms::error(), atoms::canceled()).encode(env),
+        Response::CancelRequested => atoms::ok().encode(env),
+        Response::Panic(message) => (atoms::error(), (atoms::panic(), message)).encode(env),
+        Response::EnvLost => (atoms::error(), atoms::env_lost()).encode(env),
+        Response::Released => atoms::ok().encode(env),
+        Response::PromiseState(status) => (atoms::ok(), status).encode(env),
+        Response::StreamPaused => atoms::ok().encode(env),
+        Response::StreamEnded => atoms::done().encode(env),
     }
 }
@@ -0,0 +1,651 @@
+#[cfg(feature = "fault-injection")]
+use crate::engine::Fault;
+use crate::engine::{Engine, EnvConfig, EnvId, ExecOptions, PoolId, Request, RequestId, Response};
+use crate::interrupt::{Interrupt, Termination};
+
+use deno_core::serde_json::Value;
+use std::any::Any;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+type Job = (Request, Reply);
+
+// Delivers a response to whoever issued the request: either a NIF call blocked
+// on a channel, or an Elixir process waiting for a message.
+// Replies to async requests carry the request's id so it can be canceled.
+pub(crate) struct Reply {
+    id: Option<RequestId>,
+    deliver: Box<dyn FnOnce(Response) + Send>,
+}
+
+impl Reply {
+    pub fn new(deliver: impl FnOnce(Response) + Send + 'static) -> Self {
+        Reply {
+            id: None,
+            deliver: Box::new(deliver),
+        }
+    }
+
+    pub fn tracked(id: RequestId, deliver: impl FnOnce(Response) + Send + 'static) -> Self {
+        Reply {
+            id: Some(id),
+            deliver: Box::new(deliver),
+        }
+    }
+
+    pub fn channel(sender: Sender<Response>) -> Self {
+        Reply::new(move |response| {
+            let _ = sender.send(response);
+        })
+    }
+
+    // For background work whose outcome nobody is waiting on
+    pub fn ignore() -> Self {
+        Reply::new(|_| {})
+    }
+
+    fn send(self, response: Response) {
+        (self.deliver)(response)
+    }
+}
+
+// What a worker is doing with tracked requests, shared with the manager so
+// they can be canceled from any thread while queued or running
+#[derive(Default)]
+struct Tracking {
+    queued: HashSet<RequestId>,
+    canceled: HashSet<RequestId>,
+    // Whatever is running, tracked or not, with the engine's interrupt
+    running: Option<(Option<RequestId>, Arc<Interrupt>)>,
+    // Set once the worker is removed; nothing else runs after that
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Tracker(Mutex<Tracking>);
+
+impl Tracker {
+    fn queue(&self, id: RequestId) {
+        if let Ok(mut tracking) = self.0.lock() {
+            tracking.queued.insert(id);
+        }
+    }
+
+    // Returns false if the request was canceled while it was queued, or the
+    // worker was removed
+    fn start(&self, id: Option<RequestId>, interrupt: Arc<Interrupt>) -> bool {
+        let Ok(mut tracking) = self.0.lock() else {
+            return true;
+        };
+        if let Some(id) = id {
+            tracking.queued.remove(&id);
+            if tracking.canceled.remove(&id) {
+                return false;
+            }
+        }
+        if tracking.closed {
+            return false;
+        }
+        tracking.running = Some((id, interrupt));
+        true
+    }
+
+    fn finish(&self) {
+        if let Ok(mut tracking) = self.0.lock() {
+            tracking.running = None;
+        }
+    }
+
+    // Requests that already finished are left alone
+    fn cancel(&self, id: RequestId) {
+        let Ok(mut tracking) = self.0.lock() else {
+            return;
+        };
+        match &tracking.running {
+            Some((Some(running), interrupt)) if *running == id => {
+                interrupt.fire(Termination::Canceled)
+            }
+            _ => {
+                if tracking.queued.remove(&id) {
+                    tracking.canceled.insert(id);
+                }
+            }
+        }
+    }
+
+    // Stops whatever is running, and everything still queued is answered as
+    // canceled instead of run
+    fn close(&self) {
+        let Ok(mut tracking) = self.0.lock() else {
+            return;
+        };
+        tracking.closed = true;
+        if let Some((_, interrupt)) = &tracking.running {
+            interrupt.fire(Termination::Canceled);
+        }
+    }
+}
+
+// Each environment lives on its own OS thread, since a `JsRuntime` is pinned
+// to the thread that created it. Requests for one environment queue up on
+// that thread only, so a slow script never stalls any other environment.
+struct Worker {
+    sender: Sender<Job>,
+    tracker: Arc<Tracker>,
+    config: EnvConfig,
+}
+
+impl Worker {
+    fn spawn(id: EnvId, config: EnvConfig) -> Self {
+        let (sender, receiver) = channel::<Job>();
+        let tracker = Arc::new(Tracker::default());
+        let worker_tracker = tracker.clone();
+        let worker_config = config.clone();
+
+        thread::Builder::new()
+            .name(format!("jsengine-env-{}", id))
+            .spawn(move || {
+                let config = worker_config;
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .expect("Failed to create Tokio runtime - this should never fail");
+                let mut engine = boot(&config);
+
+                // Runs until the `Worker` is dropped and the queue is drained,
+                // so the engine is always torn down on its own thread. A panic
+                // only costs the request that caused it: the engine, and with
+                // it all state in the environment, starts over.
+                while let Ok((request, reply)) = receiver.recv() {
+                    // Closes the queue before the caller hears back, as a
+                    // thread that died would
+                    #[cfg(feature = "fault-injection")]
+                    if let Request::InjectFault(_, Fault::Exit) = request {
+                        drop(receiver);
+                        drop(reply);
+                        return;
+                    }
+
+                    let served = panic::catch_unwind(AssertUnwindSafe(|| {
+                        serve(
+                            &runtime,
+                            &mut engine,
+                            &config,
+                            &worker_tracker,
+                            &request,
+                            reply.id,
+                        )
+                    }));
+                    worker_tracker.finish();
+
+                    // A cancel can still land between the engine clearing its
+                    // interrupt and `finish`. Nothing is running by now, and no
+                    // cancel can fire any more, so clear it before it stops the
+                    // next request instead.
+                    if let Ok(engine) = &engine {
+                        engine.interrupt().reset();
+                    }
+
+                    let result = match served {
+                        Ok(result) => result,
+                        Err(payload) => {
+                            reboot(&mut engine, &config);
+                            Response::Panic(panic_message(payload.as_ref()))
+                        }
+                    };
+                    reply.send(result);
+                }
+            })
+            .expect("Failed to spawn environment thread");
+
+        Worker {
+            sender,
+            tracker,
+            config,
+        }
+    }
+}
+
+// A removed environment doesn't finish its queue: a script stuck in a loop
+// would otherwise keep its thread spinning forever
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.tracker.close();
+    }
+}
+
+fn serve(
+    runtime: &tokio::runtime::Runtime,
+    engine: &mut Result<Engine, String>,
+    config: &EnvConfig,
+    tracker: &Tracker,
+    request: &Request,
+    id: Option<RequestId>,
+) -> Response {
+    // A reset starts over with a fresh engine and runs the preload list again
+    if let Request::ResetEnv(env_id) = request {
+        reboot(engine, config);
+        let files = config.options.preload.clone();
+        let preload = Request::Load(*env_id, files, ExecOptions::default());
+        return match serve(runtime, engine, config, tracker, &preload, None) {
+            Response::Result(Ok(_)) => Response::EnvReset,
+            response => response,
+        };
+    }
+
+    // An engine that failed to start is retried, and reported if it fails again
+    if engine.is_err() {
+        *engine = boot(config);
+    }
+    let current = match engine {
+        Ok(current) => current,
+        Err(message) => return Response::Panic(message.clone()),
+    };
+
+    if !tracker.start(id, current.interrupt()) {
+        return Response::Canceled;
+    }
+    #[cfg(feature = "fault-injection")]
+    if let Request::InjectFault(_, Fault::Panic) = request {
+        panic!("Panic injected by inject_fault/2");
+    }
+    let result = runtime.block_on(current.handle(request));
+
+    // A heap that hit its limit can't be trusted to recover, so the
+    // environment starts over with a fresh engine
+    if let Response::OutOfMemory = result {
+        reboot(engine, config);
+    }
+    result
+}
+
+fn boot(config: &EnvConfig) -> Result<Engine, String> {
+    panic::catch_unwind(|| Engine::new(config))
+        .unwrap_or_else(|payload| Err(panic_message(payload.as_ref())))
+}
+
+// V8 requires isolates on a thread to be dropped in reverse order of creation,
+// so the old engine has to go before its replacement is built
+fn reboot(engine: &mut Result<Engine, String>, config: &EnvConfig) {
+    let old = std::mem::replace(engine, Err("Environment is restarting".to_string()));
+    let _ = panic::catch_unwind(AssertUnwindSafe(move || drop(old)));
+    *engine = boot(config);
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Unknown panic".to_string()
+    }
+}
+
+// Environments kept warm with a preload list. Checked-in (or dropped)
+// environments are replaced by fresh ones rather than reused, so nothing a
+// caller did can leak to the next one.
+struct Pool {
+    size: usize,
+    config: EnvConfig,
+    idle: VecDeque<EnvId>,
+    checked_out: HashSet<EnvId>,
+    failures: Failures,
+}
+
+// Why the preload of a pool's environments failed, so they are discarded
+// rather than handed out
+type Failures = Arc<Mutex<HashMap<EnvId, Value>>>;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn preload_error(response: &Response) -> Option<Value> {
+    match response {
+        Response::Result(Ok(_)) => None,
+        Response::Result(Err(err)) => Some(err.clone()),
+        _ => Some(Value::String(
+            "Failed to preload pool environment".to_string(),
+        )),
+    }
+}
+
+// Replies to `CreatePool` once every environment has finished preloading
+struct Warmup {
+    pool_id: PoolId,
+    remaining: usize,
+    error: Option<Value>,
+    reply: Option<Reply>,
+}
+
+impl Warmup {
+    fn start(pool_id: PoolId, size: usize, reply: Reply) -> Arc<Mutex<Warmup>> {
+        Arc::new(Mutex::new(Warmup {
+            pool_id,
+            remaining: size,
+            error: None,
+            reply: Some(reply),
+        }))
+    }
+
+    fn reply(warmup: &Arc<Mutex<Warmup>>) -> Reply {
+        let warmup = warmup.clone();
+        Reply::new(move |response| {
+            let Ok(mut warmup) = warmup.lock() else {
+                return;
+            };
+            if let Some(err) = preload_error(&response) {
+                warmup.error.get_or_insert(err);
+            }
+
+            warmup.remaining -= 1;
+            if warmup.remaining == 0 {
+                if let Some(reply) = warmup.reply.take() {
+                    reply.send(match warmup.error.take() {
+                        Some(err) => Response::PoolFailed(warmup.pool_id, err),
+                        None => Response::PoolCreated(warmup.pool_id),
+                    });
+                }
+            }
+        })
+    }
+}
+
+pub(crate) struct EngineManager {
+    workers: HashMap<EnvId, Worker>,
+    next_id: EnvId,
+    pools: HashMap<PoolId, Pool>,
+    next_pool_id: PoolId,
+}
+
+impl EngineManager {
+    pub fn new() -> Self {
+        let mut manager = EngineManager {
+            workers: HashMap::new(),
+            next_id: 1, // 0 is reserved for default environment
+            pools: HashMap::new(),
+            next_pool_id: 1,
+        };
+        // Create default environment
+        manager
+            .workers
+            .insert(0, Worker::spawn(0, EnvConfig::default()));
+        manager
+    }
+
+    // Routes a request to the thread owning its environment. Environment
+    // bookkeeping is answered inline by returning the response, in which case
+    // `reply` is dropped unused; otherwise the worker delivers it later.
+    pub fn dispatch(&mut self, request: Request, reply: Reply) -> Option<Response> {
+        match &request {
+            Request::CreateEnv(config) => {
+                if config.options.preload.is_empty() {
+                    return Some(Response::EnvCreated(self.spawn_env(config)));
+                }
+
+                // Only handed out once its preload succeeded
+                let id = self.spawn_env(config);
+                let created = Reply::new(move |response| {
+                    reply.send(match response {
+                        Response::Result(Ok(_)) => Response::EnvCreated(id),
+                        response => Response::EnvFailed(id, Box::new(response)),
+                    })
+                });
+                self.preload(id, config, created)
+                    .map(|response| Response::EnvFailed(id, Box::new(response)))
+            }
+            Request::DestroyEnv(id) => {
+                if *id == 0 {
+                    Some(Response::Result(Err(Value::String(
+                        "Cannot destroy default environment".to_string(),
+                    ))))
+                } else if self.workers.contains_key(id) {
+                    self.release_env(*id);
+                    Some(Response::EnvDestroyed)
+                } else {
+                    Some(Response::Result(Err(Value::String(format!(
+                        "Environment {} not found",
+                        id
+                    )))))
+                }
+            }
+            Request::Load(env_id, _, _)
+            | Request::Run(env_id, _, _, _)
+            | Request::Call(env_id, _, _, _)
+            | Request::CallMany(env_id, _, _)
+            | Request::Construct(env_id, _, _, _)
+            | Request::StreamCall(env_id, _, _)
+            | Request::StreamDemand(env_id, _, _, _)
+            | Request::StreamClose(env_id, _)
+            | Request::CallExport(env_id, _, _, _, _)
+            | Request::CallHandle(env_id, _, _, _)
+            | Request::GetProperty(env_id, _, _, _)
+            | Request::SetProperty(env_id, _, _, _)
+            | Request::InvokeMethod(env_id, _, _, _, _)
+            | Request::GetGlobal(env_id, _, _)
+            | Request::SetGlobal(env_id, _, _)
+            | Request::AwaitPromise(env_id, _, _)
+            | Request::PromiseState(env_id, _)
+            | Request::Release(env_id, _)
+            | Request::ResetEnv(env_id) => {
+                let env_id = *env_id;
+                self.forward(env_id, request, reply)
+            }
+            #[cfg(feature = "fault-injection")]
+            Request::InjectFault(env_id, _) => {
+                let env_id = *env_id;
+                self.forward(env_id, request, reply)
+            }
+            Request::CreatePool(size, config) => {
+                let pool_id = self.next_pool_id;
+                self.next_pool_id += 1;
+
+                let mut pool = Pool {
+                    size: *size,
+                    config: config.clone(),
+                    idle: VecDeque::new(),
+                    checked_out: HashSet::new(),
+                    failures: Failures::default(),
+                };
+                let warmup = Warmup::start(pool_id, *size, reply);
+                for _ in 0..*size {
+                    let env_id = self.warm_env(config, &pool.failures, Warmup::reply(&warmup));
+                    pool.idle.push_back(env_id);
+                }
+                self.pools.insert(pool_id, pool);
+
+                if *size == 0 {
+                    Some(Response::PoolCreated(pool_id))
+                } else {
+                    None
+                }
+            }
+            Request::Checkout(pool_id) => {
+                let Some(pool) = self.pools.get_mut(pool_id) else {
+                    return Some(pool_not_found(*pool_id));
+                };
+
+                // Environments whose preload failed are dropped as they come up
+                let failures = pool.failures.clone();
+                let mut idle = None;
+                while let Some(env_id) = pool.idle.pop_front() {
+                    if lock(&failures).remove(&env_id).is_none() {
+                        idle = Some(env_id);
+                        break;
+                    }
+                    self.workers.remove(&env_id);
+                }
+
+                // An exhausted pool grows instead of making callers wait
+                let env_id = match idle {
+                    Some(env_id) => env_id,
+                    None => {
+                        let config = pool.config.clone();
+                        self.warm_env(&config, &failures, Reply::ignore())
+                    }
+                };
+                if let Some(pool) = self.pools.get_mut(pool_id) {
+                    pool.checked_out.insert(env_id);
+                }
+
+                // Handed out once its preload is done, and only if it succeeded.
+                // Anything queued after the preload runs once it is done, so an
+                // empty load serves to wait for it.
+                let preloaded = Reply::new(move |_| {
+                    reply.send(match lock(&failures).remove(&env_id) {
+                        Some(err) => {
+                            Response::EnvFailed(env_id, Box::new(Response::Result(Err(err))))
+                        }
+                        None => Response::CheckedOut(env_id),
+                    })
+                });
+                let wait = Request::Load(env_id, Vec::new(), ExecOptions::default());
+                self.forward(env_id, wait, preloaded)
+                    .map(|response| Response::EnvFailed(env_id, Box::new(response)))
+            }
+            Request::Checkin(pool_id, env_id) => match self.pools.get(pool_id) {
+                None => Some(pool_not_found(*pool_id)),
+                Some(pool) if !pool.checked_out.contains(env_id) => {
+                    Some(Response::Result(Err(Value::String(format!(
+                        "Environment {} is not checked out from pool {}",
+                        env_id, pool_id
+                    )))))
+                }
+                Some(_) => {
+                    self.recycle(*pool_id, *env_id);
+                    Some(Response::CheckedIn)
+                }
+            },
+            Request::Cancel(env_id, request_id) => match self.workers.get(env_id) {
+                Some(worker) => {
+                    worker.tracker.cancel(*request_id);
+                    Some(Response::CancelRequested)
+                }
+                None => Some(Response::Result(Err(Value::String(format!(
+                    "Environment {} not found",
+                    env_id
+                ))))),
+            },
+        }
+    }
+
+    // Called when the last Elixir reference to an environment is garbage
+    // collected. Dropping the worker stops it, and lets its thread drain and
+    // drop the engine.
+    pub fn release_env(&mut self, id: EnvId) {
+        if id == 0 {
+            return;
+        }
+
+        let pool_id = self
+            .pools
+            .iter()
+            .find(|(_, pool)| pool.checked_out.contains(&id))
+            .map(|(pool_id, _)| *pool_id);
+        match pool_id {
+            Some(pool_id) => self.recycle(pool_id, id),
+            None => {
+                self.workers.remove(&id);
+            }
+        }
+    }
+
+    // Called when the last Elixir reference to a pool is garbage collected.
+    // Checked-out environments live on until their own handles are released.
+    pub fn release_pool(&mut self, pool_id: PoolId) {
+        if let Some(pool) = self.pools.remove(&pool_id) {
+            for env_id in pool.idle {
+                self.workers.remove(&env_id);
+            }
+        }
+    }
+
+    fn spawn_env(&mut self, config: &EnvConfig) -> EnvId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.workers.insert(id, Worker::spawn(id, config.clone()));
+        id
+    }
+
+    // Spawns an environment for a pool and queues its preload, recording a
+    // failure in `failures`. It can join the pool right away, since anything
+    // sent to it afterwards runs once preloading is done.
+    fn warm_env(&mut self, config: &EnvConfig, failures: &Failures, reply: Reply) -> EnvId {
+        let id = self.spawn_env(config);
+        let recorded = failures.clone();
+        let watched = Reply::new(move |response| {
+            if let Some(err) = preload_error(&response) {
+                lock(&recorded).insert(id, err);
+            }
+            reply.send(response);
+        });
+
+        // Can only be answered inline if the new thread died right away
+        if let Some(err) = self
+            .preload(id, config, watched)
+            .as_ref()
+            .and_then(preload_error)
+        {
+            lock(failures).insert(id, err);
+        }
+        id
+    }
+
+    fn preload(&mut self, id: EnvId, config: &EnvConfig, reply: Reply) -> Option<Response> {
+        let files = config.options.preload.clone();
+        self.forward(id, Request::Load(id, files, ExecOptions::default()), reply)
+    }
+
+    // Retires a returned environment, replacing it with a freshly preloaded one
+    // unless the pool already has `size` idle, as it may after a burst of
+    // checkouts grew it
+    fn recycle(&mut self, pool_id: PoolId, env_id: EnvId) {
+        self.workers.remove(&env_id);
+
+        let Some(pool) = self.pools.get_mut(&pool_id) else {
+            return;
+        };
+        pool.checked_out.remove(&env_id);
+        if pool.idle.len() >= pool.size {
+            return;
+        }
+        let (config, failures) = (pool.config.clone(), pool.failures.clone());
+
+        let replacement = self.warm_env(&config, &failures, Reply::ignore());
+        if let Some(pool) = self.pools.get_mut(&pool_id) {
+            pool.idle.push_back(replacement);
+        }
+    }
+
+    fn forward(&mut self, env_id: EnvId, request: Request, reply: Reply) -> Option<Response> {
+        let Some(worker) = self.workers.get(&env_id) else {
+            return Some(Response::Result(Err(Value::String(format!(
+                "Environment {} not found",
+                env_id
+            )))));
+        };
+
+        // Tracked before it is sent, so the worker always finds it
+        if let Some(id) = reply.id {
+            worker.tracker.queue(id);
+        }
+        match worker.sender.send((request, reply)) {
+            Ok(()) => None,
+            Err(_) => {
+                // The thread is gone, and everything in the environment with
+                // it. A fresh one takes its place for later requests.
+                let config = worker.config.clone();
+                self.workers.insert(env_id, Worker::spawn(env_id, config));
+                Some(Response::EnvLost)
+            }
+        }
+    }
+}
+
+fn pool_not_found(pool_id: PoolId) -> Response {
+    Response::Result(Err(Value::String(format!("Pool {} not found", pool_id))))
+}
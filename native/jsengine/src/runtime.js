@@ -18,7 +18,11 @@
   });
 
   globalThis.setTimeout = function(handler, timeout = 0) {
-    core.ops.op_set_timeout(timeout).then(handler);
+    core.ops.op_set_timeout(timeout).then((fired) => {
+      if (fired) {
+        handler();
+      }
+    });
   };
 
 })(globalThis);
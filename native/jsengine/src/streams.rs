@@ -0,0 +1,55 @@
+use crate::conv::{Exported, Terms};
+use crate::engine::ExecOptions;
+
+use deno_core::serde_json::Value;
+use deno_core::v8;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub(crate) type StreamId = u64;
+
+// Hands each value a stream yields to Elixir, from the engine thread
+pub(crate) type StreamSink = Arc<dyn Fn(Exported) + Send + Sync>;
+
+/// Everything needed to start streaming the iterator a function returns.
+pub(crate) struct StreamStart {
+    pub id: StreamId,
+    pub path: Vec<String>,
+    pub args: Terms,
+    // How many values to send before waiting for more demand
+    pub demand: usize,
+    pub sink: StreamSink,
+}
+
+/// An iterator being streamed to Elixir, driven only while it has credit.
+pub(crate) struct Stream {
+    pub iterator: v8::Global<v8::Object>,
+    pub next: v8::Global<v8::Function>,
+    pub sink: StreamSink,
+    pub credit: usize,
+    pub opts: ExecOptions,
+}
+
+#[derive(Default)]
+pub(crate) struct Streams {
+    streams: HashMap<StreamId, Stream>,
+}
+
+impl Streams {
+    pub fn insert(&mut self, id: StreamId, stream: Stream) {
+        self.streams.insert(id, stream);
+    }
+
+    pub fn get_mut(&mut self, id: StreamId) -> Result<&mut Stream, Value> {
+        self.streams.get_mut(&id).ok_or_else(|| {
+            Value::String(format!(
+                "Stream {} is no longer open; it finished, was closed or its environment was reset",
+                id
+            ))
+        })
+    }
+
+    pub fn remove(&mut self, id: StreamId) -> Option<Stream> {
+        self.streams.remove(&id)
+    }
+}
@@ -0,0 +1,6 @@
+// Scaler module that imports math and leaves the global object alone
+import { multiply } from './math.js';
+
+export function scale(values, factor) {
+  return values.map((value) => multiply(value, factor));
+}
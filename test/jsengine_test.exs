@@ -2,6 +2,10 @@ defmodule JSEngineTest do
   use ExUnit.Case, async: false
   doctest JSEngine
 
+  defmodule Point do
+    defstruct x: 0, y: 0, label: nil
+  end
+
   describe "run/1" do
     test "executes simple JavaScript code" do
       assert {:ok, nil} = JSEngine.run("var x = 1;")
@@ -117,6 +121,23 @@ defmodule JSEngineTest do
       assert {:ok, nil} = JSEngine.run("function throwError() { throw new Error('oops'); }")
       assert {:error, _} = JSEngine.call("throwError", [])
     end
+
+    test "calls a function by dotted path" do
+      assert {:ok, _} = JSEngine.run("globalThis.MyLib = { utils: { double: (x) => x * 2 } }")
+      assert {:ok, 42} = JSEngine.call("MyLib.utils.double", [21])
+      assert {:ok, 42} = JSEngine.call(["MyLib", "utils", "double"], [21])
+    end
+
+    test "binds this to the parent object" do
+      assert {:ok, _} =
+               JSEngine.run("globalThis.counter = { count: 5, next() { return ++this.count; } }")
+
+      assert {:ok, 6} = JSEngine.call("counter.next", [])
+    end
+
+    test "returns error when a path segment is missing" do
+      assert {:error, _} = JSEngine.call("missing.utils.format", [])
+    end
   end
 
   describe "load/1" do
@@ -173,6 +194,509 @@ defmodule JSEngineTest do
     end
   end
 
+  describe "async requests" do
+    test "run_async/1 replies with a message" do
+      assert {:ok, ref} = JSEngine.run_async("6 * 7")
+      assert_receive {:jsengine, ^ref, {:ok, 42}}
+    end
+
+    test "call_async/2 does not block while a promise is pending" do
+      assert {:ok, nil} =
+               JSEngine.run("function later(x) { return new Promise(r => setTimeout(() => r(x), 50)); }")
+
+      assert {:ok, ref1} = JSEngine.call_async("later", [1])
+      assert {:ok, ref2} = JSEngine.call_async("later", [2])
+      assert {:ok, 1} = JSEngine.await(ref1)
+      assert {:ok, 2} = JSEngine.await(ref2)
+    end
+
+    test "async errors are delivered as messages" do
+      assert {:ok, ref} = JSEngine.run_async("throw new Error('async boom')")
+      assert {:error, _} = JSEngine.await(ref)
+    end
+
+    test "unknown environments fail immediately" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert :ok = JSEngine.destroy_env(env)
+      assert {:error, _} = JSEngine.run_async(env, "1")
+    end
+  end
+
+  describe "cancellation" do
+    test "cancels a running script and keeps the environment usable" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:ok, ref} = JSEngine.run_async(env, "while (true) {}")
+      Process.sleep(50)
+      assert :ok = JSEngine.cancel(ref)
+      assert {:error, :canceled} = JSEngine.await(ref)
+      assert {:ok, 2} = JSEngine.run(env, "1 + 1")
+    end
+
+    test "cancels a queued request" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:ok, busy} = JSEngine.run_async(env, "const end = Date.now() + 200; while (Date.now() < end) {}")
+      assert {:ok, queued} = JSEngine.run_async(env, "globalThis.ran = true")
+      assert :ok = JSEngine.cancel(queued)
+      assert {:ok, nil} = JSEngine.await(busy)
+      assert {:error, :canceled} = JSEngine.await(queued)
+      assert {:ok, "undefined"} = JSEngine.run(env, "typeof ran")
+    end
+
+    test "destroying an environment stops its script and cancels what is queued" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:ok, ref} = JSEngine.run_async(env, "while (true) {}")
+      assert {:ok, queued} = JSEngine.run_async(env, "1 + 1")
+      Process.sleep(50)
+      assert :ok = JSEngine.destroy_env(env)
+      assert {:error, :canceled} = JSEngine.await(ref)
+      assert {:error, :canceled} = JSEngine.await(queued)
+    end
+
+    test "drops timers started by the canceled request" do
+      assert {:ok, env} = JSEngine.create_env()
+
+      assert {:ok, nil} =
+               JSEngine.run(env, """
+               globalThis.fired = false;
+               function wait() {
+                 return new Promise(r => setTimeout(() => { globalThis.fired = true; r(); }, 100));
+               }
+               """)
+
+      assert {:ok, ref} = JSEngine.call_async(env, "wait", [])
+      assert :ok = JSEngine.cancel(ref)
+      assert {:error, :canceled} = JSEngine.await(ref)
+
+      assert {:ok, false} =
+               JSEngine.run(env, "new Promise(r => setTimeout(() => r(globalThis.fired), 200))")
+    end
+
+    test "canceling a finished request has no effect" do
+      assert {:ok, ref} = JSEngine.run_async("1 + 1")
+      assert {:ok, 2} = JSEngine.await(ref)
+      assert :ok = JSEngine.cancel(ref)
+    end
+  end
+
+  describe "execution timeouts" do
+    test "stops a busy loop and keeps the environment usable" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:error, :timeout} = JSEngine.run(env, "while (true) {}", timeout_ms: 100)
+      assert {:ok, 2} = JSEngine.run(env, "1 + 1")
+    end
+
+    test "stops a call waiting on a promise" do
+      assert {:ok, env} = JSEngine.create_env()
+
+      assert {:ok, nil} =
+               JSEngine.run(env, "function never() { return new Promise(r => setTimeout(r, 10000)); }")
+
+      assert {:error, :timeout} = JSEngine.call(env, "never", [], timeout_ms: 100)
+      assert {:ok, "still here"} = JSEngine.run(env, "'still here'")
+    end
+
+    test "does not affect scripts that finish in time" do
+      assert {:ok, 3} = JSEngine.run("1 + 2", timeout_ms: 1000)
+    end
+  end
+
+  describe "heap limits" do
+    test "reports running out of memory as an error" do
+      assert {:ok, env} = JSEngine.create_env(max_heap_size: 32 * 1024 * 1024)
+
+      assert {:error, :out_of_memory} =
+               JSEngine.run(env, "const hog = []; while (true) { hog.push(new Array(100000).fill(0)); }")
+
+      # The environment is reset and can be used again
+      assert {:ok, 2} = JSEngine.run(env, "1 + 1")
+    end
+  end
+
+  describe "engine failures" do
+    test "a panic fails the request and restarts the environment" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:ok, 1} = JSEngine.run(env, "globalThis.before = 1")
+      assert {:error, {:panic, _}} = JSEngine.inject_fault(env, :panic)
+      assert {:ok, "undefined"} = JSEngine.run(env, "typeof before")
+    end
+
+    test "an environment whose thread is gone is replaced" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:error, :env_lost} = JSEngine.inject_fault(env, :exit)
+      assert {:error, :env_lost} = JSEngine.run(env, "1 + 1")
+      assert {:ok, 2} = JSEngine.run(env, "1 + 1")
+    end
+  end
+
+  describe "resetting environments" do
+    test "clears globals but keeps the same handle" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:ok, 1} = JSEngine.run(env, "globalThis.leftover = 1")
+      assert :ok = JSEngine.reset_env(env)
+      assert {:ok, "undefined"} = JSEngine.run(env, "typeof leftover")
+    end
+
+    test "runs the preload list again" do
+      preload = Path.join(System.tmp_dir!(), "reset_preload_#{:rand.uniform(10000)}.js")
+      File.write!(preload, "var counter = 0; function bump() { return ++counter; }")
+      on_exit(fn -> File.rm(preload) end)
+
+      assert {:ok, env} = JSEngine.create_env(preload: [preload])
+      assert {:ok, 1} = JSEngine.call(env, "bump", [])
+      assert {:ok, 2} = JSEngine.call(env, "bump", [])
+      assert :ok = JSEngine.reset_env(env)
+      assert {:ok, 1} = JSEngine.call(env, "bump", [])
+    end
+
+    test "the default environment can be reset" do
+      assert {:ok, 1} = JSEngine.run("globalThis.defaultLeftover = 1")
+      assert :ok = JSEngine.reset_env()
+      assert {:ok, "undefined"} = JSEngine.run("typeof defaultLeftover")
+    end
+
+    test "preload failures fail environment creation" do
+      assert {:error, _} = JSEngine.create_env(preload: ["/nonexistent/file.js"])
+    end
+  end
+
+  describe "environment pools" do
+    setup do
+      preload = Path.join(System.tmp_dir!(), "pool_preload_#{:rand.uniform(10000)}.js")
+      File.write!(preload, "var counter = 0; function bump() { return ++counter; }")
+      on_exit(fn -> File.rm(preload) end)
+      {:ok, preload: preload}
+    end
+
+    test "checked out environments are preloaded", %{preload: preload} do
+      assert {:ok, pool} = JSEngine.create_pool(2, preload: [preload])
+      assert {:ok, env} = JSEngine.checkout(pool)
+      assert {:ok, 1} = JSEngine.call(env, "bump", [])
+      assert :ok = JSEngine.checkin(pool, env)
+    end
+
+    test "checked in environments are not handed out again dirty", %{preload: preload} do
+      assert {:ok, pool} = JSEngine.create_pool(1, preload: [preload])
+      assert {:ok, env} = JSEngine.checkout(pool)
+      assert {:ok, 1} = JSEngine.call(env, "bump", [])
+      assert {:ok, "dirty"} = JSEngine.run(env, "globalThis.leftover = 'dirty'")
+      assert :ok = JSEngine.checkin(pool, env)
+
+      assert {:ok, env} = JSEngine.checkout(pool)
+      assert {:ok, 1} = JSEngine.call(env, "bump", [])
+      assert {:ok, "undefined"} = JSEngine.run(env, "typeof leftover")
+    end
+
+    test "an exhausted pool still hands out environments", %{preload: preload} do
+      assert {:ok, pool} = JSEngine.create_pool(1, preload: [preload])
+      assert {:ok, _env1} = JSEngine.checkout(pool)
+      assert {:ok, env2} = JSEngine.checkout(pool)
+      assert {:ok, 1} = JSEngine.call(env2, "bump", [])
+    end
+
+    test "preload failures are reported" do
+      assert {:error, _} = JSEngine.create_pool(1, preload: ["/nonexistent/file.js"])
+    end
+
+    test "environments that fail to preload are not handed out", %{preload: preload} do
+      assert {:ok, pool} = JSEngine.create_pool(1, preload: [preload])
+      File.rm!(preload)
+
+      assert {:ok, env} = JSEngine.checkout(pool)
+      assert {:ok, 1} = JSEngine.call(env, "bump", [])
+      assert {:error, _} = JSEngine.checkout(pool)
+      assert :ok = JSEngine.checkin(pool, env)
+      assert {:error, _} = JSEngine.checkout(pool)
+    end
+
+    test "only checked out environments can be checked in" do
+      assert {:ok, pool} = JSEngine.create_pool(1)
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:error, _} = JSEngine.checkin(pool, env)
+    end
+  end
+
+  describe "startup snapshots" do
+    setup do
+      base = Path.join(System.tmp_dir!(), "snapshot_#{:rand.uniform(10000)}")
+      source = base <> ".js"
+      snapshot = base <> ".bin"
+      File.write!(source, "var greeting = 'hello'; function greet(name) { return greeting + ' ' + name; }")
+      on_exit(fn -> Enum.each([source, snapshot], &File.rm/1) end)
+      {:ok, source: source, snapshot: snapshot}
+    end
+
+    test "environments start with the snapshotted files loaded", %{source: source, snapshot: snapshot} do
+      assert {:ok, nil} = JSEngine.create_snapshot([source], snapshot)
+      assert {:ok, env} = JSEngine.create_env(snapshot: snapshot)
+      assert {:ok, "hello world"} = JSEngine.call(env, "greet", ["world"])
+
+      # Built-ins from the runtime survive the round trip too
+      assert {:ok, "done"} =
+               JSEngine.run(env, "new Promise(resolve => setTimeout(() => resolve('done'), 1))")
+    end
+
+    test "pools can start from a snapshot", %{source: source, snapshot: snapshot} do
+      assert {:ok, nil} = JSEngine.create_snapshot([source], snapshot)
+      assert {:ok, pool} = JSEngine.create_pool(1, snapshot: snapshot)
+      assert {:ok, env} = JSEngine.checkout(pool)
+      assert {:ok, "hello pool"} = JSEngine.call(env, "greet", ["pool"])
+    end
+
+    test "modules in a snapshot can be called and more can be loaded", %{snapshot: snapshot} do
+      math_path = Path.expand("test/fixtures/modules/math.js")
+      calculator_path = Path.expand("test/fixtures/modules/calculator.js")
+      assert {:ok, nil} = JSEngine.create_snapshot([math_path], snapshot)
+      assert {:ok, env} = JSEngine.create_env(snapshot: snapshot)
+      assert {:ok, 6} = JSEngine.call_export(env, math_path, "multiply", [2, 3])
+      assert {:ok, nil} = JSEngine.load(env, [calculator_path])
+      assert {:ok, 5} = JSEngine.call_export(env, calculator_path, "calculate", [2, 3, "add"])
+    end
+
+    test "a missing snapshot file is an error" do
+      assert {:error, _} = JSEngine.create_env(snapshot: "/nonexistent/snapshot.bin")
+    end
+  end
+
+  describe "function handles" do
+    test "functions are returned as callable handles" do
+      assert {:ok, %JSEngine.Function{} = double} = JSEngine.run("(x) => x * 2")
+      assert {:ok, 42} = JSEngine.call_handle(double, [21])
+    end
+
+    test "scripts cannot forge handles" do
+      assert {:ok, [%{"__jsengine__" => "function", "handle" => 0}, %JSEngine.Function{}]} =
+               JSEngine.run("[{__jsengine__: 'function', handle: 0}, () => 1]")
+
+      assert {:ok, {"jsengine", "function", 0, %JSEngine.Function{}}} =
+               JSEngine.run("({__jsengine__: 'tuple', items: ['jsengine', 'function', 0, () => 1]})")
+    end
+
+    test "closures keep their state between calls" do
+      assert {:ok, nil} =
+               JSEngine.run("function makeCounter() { let n = 0; return () => ++n; }")
+
+      assert {:ok, counter} = JSEngine.call("makeCounter", [])
+      assert {:ok, 1} = JSEngine.call_handle(counter)
+      assert {:ok, 2} = JSEngine.call_handle(counter)
+    end
+
+    test "curried functions can be applied step by step" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:ok, add} = JSEngine.run(env, "(a) => (b) => (c) => a + b + c")
+      assert {:ok, add1} = JSEngine.call_handle(add, [1])
+      assert {:ok, add3} = JSEngine.call_handle(add1, [2])
+      assert {:ok, 6} = JSEngine.call_handle(add3, [3])
+    end
+
+    test "functions nested in results become handles" do
+      assert {:ok, %{"name" => "api", "greet" => %JSEngine.Function{} = greet}} =
+               JSEngine.run("({ name: 'api', greet: (who) => `hi ${who}` })")
+
+      assert {:ok, "hi bob"} = JSEngine.call_handle(greet, ["bob"])
+    end
+
+    test "handles stop working once their environment is reset" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:ok, fun} = JSEngine.run(env, "() => 1")
+      assert :ok = JSEngine.reset_env(env)
+      assert {:error, _} = JSEngine.call_handle(fun)
+    end
+  end
+
+  describe "object handles" do
+    setup do
+      {:ok, env} = JSEngine.create_env()
+
+      {:ok, nil} =
+        JSEngine.run(env, """
+        class Counter {
+          constructor(start) { this.count = start; this.label = 'counter'; }
+          increment(by = 1) { this.count += by; return this.count; }
+          self() { return this; }
+        }
+        function makeCounter(start) { return new Counter(start); }
+        """)
+
+      {:ok, env: env}
+    end
+
+    test "results can be kept in the environment as handles", %{env: env} do
+      assert {:ok, %JSEngine.Handle{} = counter} =
+               JSEngine.call(env, "makeCounter", [5], return: :handle)
+
+      assert {:ok, 5} = JSEngine.get_property(counter, "count")
+      assert {:ok, 6} = JSEngine.invoke_method(counter, "increment")
+      assert {:ok, 9} = JSEngine.invoke_method(counter, "increment", [3])
+      assert {:ok, 9} = JSEngine.get_property(counter, "count")
+    end
+
+    test "properties can be set on handles", %{env: env} do
+      assert {:ok, counter} = JSEngine.call(env, "makeCounter", [0], return: :handle)
+      assert {:ok, nil} = JSEngine.set_property(counter, "count", 41)
+      assert {:ok, 42} = JSEngine.invoke_method(counter, "increment")
+      assert {:ok, nil} = JSEngine.set_property(counter, "meta", %{"tags" => ["a"]})
+      assert {:ok, "a"} = JSEngine.get_property(counter, "meta.tags.0")
+    end
+
+    test "method results can be handles too", %{env: env} do
+      assert {:ok, counter} = JSEngine.run(env, "makeCounter(1)", return: :handle)
+      assert {:ok, %JSEngine.Handle{} = same} =
+               JSEngine.invoke_method(counter, "self", [], return: :handle)
+
+      assert {:ok, 2} = JSEngine.invoke_method(same, "increment")
+      assert {:ok, 2} = JSEngine.get_property(counter, "count")
+    end
+
+    test "released handles can no longer be used", %{env: env} do
+      assert {:ok, counter} = JSEngine.call(env, "makeCounter", [0], return: :handle)
+      assert :ok = JSEngine.release(counter)
+      assert {:error, _} = JSEngine.get_property(counter, "count")
+    end
+  end
+
+  describe "detached promises" do
+    test "promises can be returned without waiting for them" do
+      assert {:ok, env} = JSEngine.create_env()
+
+      code = "new Promise((resolve) => setTimeout(() => resolve(42), 50))"
+      assert {:ok, %JSEngine.Promise{} = promise} = JSEngine.run(env, code, await: false)
+
+      assert {:ok, :pending} = JSEngine.promise_state(promise)
+      assert {:ok, 2} = JSEngine.run(env, "1 + 1")
+      assert {:ok, 42} = JSEngine.await_promise(promise)
+      assert {:ok, :fulfilled} = JSEngine.promise_state(promise)
+    end
+
+    test "calls can leave their promise pending" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:ok, nil} = JSEngine.run(env, "async function later(x) { return x * 2; }")
+      assert {:ok, promise} = JSEngine.call(env, "later", [21], await: false)
+      assert {:ok, 42} = JSEngine.await_promise(promise, 1000)
+    end
+
+    test "rejections are reported when awaited" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:ok, promise} = JSEngine.run(env, "Promise.reject(new Error('nope'))", await: false)
+      assert {:ok, :rejected} = JSEngine.promise_state(promise)
+      assert {:ok, 1} = JSEngine.run(env, "1")
+      assert {:error, error} = JSEngine.await_promise(promise)
+      assert error =~ "nope"
+    end
+
+    test "awaiting can time out and be retried" do
+      assert {:ok, env} = JSEngine.create_env()
+
+      code = "new Promise((resolve) => setTimeout(() => resolve('done'), 300))"
+      assert {:ok, promise} = JSEngine.run(env, code, await: false)
+
+      assert {:error, :timeout} = JSEngine.await_promise(promise, 10)
+      assert {:ok, "done"} = JSEngine.await_promise(promise, 2000)
+    end
+
+    test "values that are not promises are returned as usual" do
+      assert {:ok, 3} = JSEngine.run("1 + 2", await: false)
+    end
+  end
+
+  describe "streaming" do
+    setup do
+      {:ok, env} = JSEngine.create_env()
+
+      {:ok, nil} =
+        JSEngine.run(env, """
+        globalThis.countTo = function* (n) { for (let i = 1; i <= n; i++) yield i; };
+        globalThis.ticks = async function* (n) {
+          for (let i = 0; i < n; i++) {
+            await new Promise((resolve) => setTimeout(resolve, 1));
+            yield { tick: i };
+          }
+        };
+        globalThis.naturals = function* () { let i = 0; while (true) yield i++; };
+        globalThis.broken = async function* () { yield 1; throw new Error('broken'); };
+        """)
+
+      {:ok, env: env}
+    end
+
+    test "values are sent as messages", %{env: env} do
+      assert {:ok, %JSEngine.Stream{ref: ref}} = JSEngine.stream_call(env, "countTo", [3])
+      assert_receive {:jsengine, ^ref, {:next, 1}}
+      assert_receive {:jsengine, ^ref, {:next, 2}}
+      assert_receive {:jsengine, ^ref, {:next, 3}}
+      assert_receive {:jsengine, ^ref, :done}
+    end
+
+    test "demand limits how much is sent until acknowledged", %{env: env} do
+      assert {:ok, %JSEngine.Stream{ref: ref} = stream} =
+               JSEngine.stream_call(env, "naturals", [], demand: 2)
+
+      assert_receive {:jsengine, ^ref, {:next, 0}}
+      assert_receive {:jsengine, ^ref, {:next, 1}}
+      refute_receive {:jsengine, ^ref, _}, 50
+
+      assert {:ok, 2} = JSEngine.run(env, "1 + 1")
+      assert :ok = JSEngine.stream_ack(stream, 1)
+      assert_receive {:jsengine, ^ref, {:next, 2}}
+      assert :ok = JSEngine.stream_close(stream)
+    end
+
+    test "streams wait for acknowledgement by default", %{env: env} do
+      assert {:ok, %JSEngine.Stream{ref: ref} = stream} =
+               JSEngine.stream_call(env, "naturals", [])
+
+      for i <- 0..99, do: assert_receive({:jsengine, ^ref, {:next, ^i}})
+      refute_receive {:jsengine, ^ref, _}, 50
+
+      assert {:ok, 2} = JSEngine.run(env, "1 + 1")
+      assert :ok = JSEngine.stream_close(stream)
+    end
+
+    test "streams are closed once no longer referenced", %{env: env} do
+      {:ok, nil} =
+        JSEngine.run(env, """
+        globalThis.watched = function* () {
+          try { while (true) yield 1; } finally { globalThis.watchedClosed = true; }
+        };
+        """)
+
+      test = self()
+
+      {pid, monitor} =
+        spawn_monitor(fn ->
+          {:ok, %JSEngine.Stream{ref: ref}} =
+            JSEngine.stream_call(env, "watched", [], to: test, demand: 1)
+
+          send(test, {:started, ref})
+        end)
+
+      assert_receive {:started, ref}
+      assert_receive {:DOWN, ^monitor, :process, ^pid, :normal}
+      assert_receive {:jsengine, ^ref, {:next, 1}}
+      Process.sleep(50)
+      assert {:ok, true} = JSEngine.run(env, "globalThis.watchedClosed === true")
+    end
+
+    test "async generators stream as an Elixir Stream", %{env: env} do
+      assert [%{"tick" => 0}, %{"tick" => 1}, %{"tick" => 2}] =
+               JSEngine.stream(env, "ticks", [3]) |> Enum.to_list()
+    end
+
+    test "infinite iterators can be taken from lazily", %{env: env} do
+      assert [0, 1, 2, 3, 4] = JSEngine.stream(env, "naturals", [], demand: 2) |> Enum.take(5)
+    end
+
+    test "errors end the stream", %{env: env} do
+      assert {:ok, %JSEngine.Stream{ref: ref}} = JSEngine.stream_call(env, "broken", [])
+      assert_receive {:jsengine, ^ref, {:next, 1}}
+      assert_receive {:jsengine, ^ref, {:error, _}}
+    end
+
+    test "non-iterables are rejected", %{env: env} do
+      assert {:ok, %JSEngine.Stream{ref: ref}} = JSEngine.stream_call(env, "Math.max", [1, 2])
+      assert_receive {:jsengine, ^ref, {:error, _}}
+    end
+  end
+
   describe "complex data interchange" do
     test "handles deeply nested objects" do
       code = """
@@ -194,6 +718,48 @@ defmodule JSEngineTest do
         JSEngine.call("deepNest", [])
     end
 
+    test "binaries are passed as Uint8Array" do
+      assert {:ok, nil} = JSEngine.run("function describe(b) { return [b.constructor.name, b.length, b[0]]; }")
+      assert {:ok, ["Uint8Array", 3, 255]} = JSEngine.call("describe", [<<255, 0, 1>>])
+      assert {:ok, ["Uint8Array", 2, 104]} = JSEngine.call("describe", [{:binary, "hi"}])
+      assert {:ok, ["String", 2, "h"]} = JSEngine.call("describe", ["hi"])
+    end
+
+    test "typed arrays and array buffers come back as tagged binaries" do
+      assert {:ok, {:binary, <<1, 2, 3>>}} = JSEngine.run("new Uint8Array([1, 2, 3])")
+      assert {:ok, {:binary, <<0, 0>>}} = JSEngine.run("new ArrayBuffer(2)")
+      assert {:ok, {:binary, <<1, 0>>}} = JSEngine.run("new Uint16Array([1])")
+      assert {:ok, %{"bytes" => {:binary, <<9>>}}} =
+               JSEngine.run("({ bytes: new Uint8Array([9]) })")
+      assert {:ok, {:binary, "hi"}} = JSEngine.run("new Uint8Array([104, 105])")
+    end
+
+    test "binaries round-trip unchanged" do
+      data = :crypto.strong_rand_bytes(1024)
+      assert {:ok, nil} = JSEngine.run("function echo(b) { return b; }")
+      assert {:ok, {:binary, ^data}} = JSEngine.call("echo", [{:binary, data}])
+      assert {:ok, {:binary, "text"}} = JSEngine.call("echo", [{:binary, "text"}])
+      assert {:ok, "text"} = JSEngine.call("echo", ["text"])
+    end
+
+    test "big integers round-trip as BigInt" do
+      id = 170_141_183_460_469_231_731_687_303_715_884_105_727
+      assert {:ok, nil} = JSEngine.run("function kind(x) { return typeof x; }")
+      assert {:ok, nil} = JSEngine.run("function echo(x) { return x; }")
+      assert {:ok, "bigint"} = JSEngine.call("kind", [id])
+      assert {:ok, ^id} = JSEngine.call("echo", [id])
+      assert {:ok, ^id} = JSEngine.run("#{id}n")
+      assert {:ok, -18_446_744_073_709_551_616} = JSEngine.run("-(2n ** 64n)")
+      assert {:ok, 42} = JSEngine.run("42n")
+    end
+
+    test "integers beyond 2^53 can be passed as BigInt" do
+      assert {:ok, nil} = JSEngine.run("function inc(x) { return typeof x === 'bigint' ? x + 1n : x + 1; }")
+      big = 9_007_199_254_740_993
+      assert {:ok, 9_007_199_254_740_994} = JSEngine.call("inc", [big], bigint: true)
+      assert {:ok, 2} = JSEngine.call("inc", [1], bigint: true)
+    end
+
     test "handles large arrays" do
       assert {:ok, nil} = JSEngine.run("function range(n) { return Array.from({length: n}, (_, i) => i); }")
       assert {:ok, result} = JSEngine.call("range", [100])
@@ -202,6 +768,96 @@ defmodule JSEngineTest do
       assert Enum.at(result, 99) == 99
     end
 
+    test "tuples are passed as arrays" do
+      assert {:ok, nil} = JSEngine.run("function echo(x) { return x; }")
+      assert {:ok, ["Ok", 1]} = JSEngine.call("echo", [{:ok, 1}])
+      assert {:ok, [[1, [2, 3]]]} = JSEngine.call("echo", [[{1, {2, 3}}]])
+    end
+
+    test "tuples can be passed tagged and come back as tuples" do
+      assert {:ok, nil} = JSEngine.run("function items(x) { return [x.__jsengine__, x.items]; }")
+      assert {:ok, ["tuple", ["Ok", 1]]} = JSEngine.call("items", [{:ok, 1}], tuples: :tagged)
+      assert {:ok, nil} = JSEngine.run("function echo(x) { return x; }")
+      assert {:ok, {"Err", {1, 2}}} = JSEngine.call("echo", [{:error, {1, 2}}], tuples: :tagged)
+    end
+
+    test "keyword lists are passed as objects" do
+      assert {:ok, nil} = JSEngine.run("function opts(o) { return [o.limit, o.name, Array.isArray(o)]; }")
+      assert {:ok, [5, "x", false]} = JSEngine.call("opts", [[limit: 5, name: "x"]])
+      assert {:ok, nil} = JSEngine.run("function len(x) { return x.length; }")
+      assert {:ok, 0} = JSEngine.call("len", [[]])
+    end
+
+    test "lists repeating a key are passed as arrays" do
+      assert {:ok, nil} = JSEngine.run("function echo(x) { return x; }")
+      assert {:ok, [["Ok", 1], ["Ok", 2]]} = JSEngine.call("echo", [[{:ok, 1}, {:ok, 2}]])
+      assert {:ok, [["limit", 1], ["limit", 2]]} = JSEngine.call("echo", [[limit: 1, limit: 2]])
+    end
+
+    test "structs are passed as objects naming their module" do
+      assert {:ok, nil} = JSEngine.run("function fields(x) { return [x.__struct__, x.x, x.label]; }")
+      assert {:ok, ["JSEngineTest.Point", 1, nil]} = JSEngine.call("fields", [%Point{x: 1}])
+    end
+
+    test "objects naming a loaded struct come back as that struct" do
+      assert {:ok, nil} =
+               JSEngine.run("function move(p) { return {...p, x: p.x + 1, extra: true}; }")
+
+      assert {:ok, %Point{x: 2, y: 2, label: "a"}} =
+               JSEngine.call("move", [%Point{x: 1, y: 2, label: "a"}])
+
+      assert {:ok, [%Point{x: 5, y: 0}]} =
+               JSEngine.run("[{__struct__: 'JSEngineTest.Point', x: 5}]")
+
+      assert {:ok, %{"__struct__" => "No.Such.Module", "x" => 5}} =
+               JSEngine.run("({__struct__: 'No.Such.Module', x: 5})")
+    end
+
+    test "objects naming a module loaded later come back as its struct" do
+      code = "({__struct__: 'JSEngineTest.Later', x: 1})"
+      assert {:ok, %{"__struct__" => "JSEngineTest.Later"}} = JSEngine.run(code)
+
+      defmodule Later do
+        defstruct x: 0, y: 0
+      end
+
+      assert {:ok, %{__struct__: JSEngineTest.Later, x: 1, y: 0}} = JSEngine.run(code)
+    end
+
+    test "the structs option narrows which structs objects come back as" do
+      assert {:ok, %Point{x: 5}} =
+               JSEngine.run("({__struct__: 'JSEngineTest.Point', x: 5})", structs: [Point])
+
+      assert {:ok, %{"__struct__" => "JSEngineTest.Point"}} =
+               JSEngine.run("({__struct__: 'JSEngineTest.Point', x: 5})", structs: [URI])
+    end
+
+    test "large payloads round-trip" do
+      assert {:ok, nil} = JSEngine.run("function echo(x) { return x; }")
+      payload = for i <- 1..10_000, do: %{"id" => i, "name" => "item #{i}", "tags" => ["a", "b"], "score" => i + 0.5}
+      assert {:ok, ^payload} = JSEngine.call("echo", [payload])
+    end
+
+    test "values nested too deeply or referring to themselves are rejected" do
+      assert {:error, _} = JSEngine.run("(() => { const cyclic = {}; cyclic.self = cyclic; return cyclic; })()")
+
+      assert {:error, _} =
+               JSEngine.run("(() => { let deep = []; for (let i = 0; i < 10000; i++) deep = [deep]; return deep; })()")
+
+      assert {:ok, nil} = JSEngine.run("function echo(x) { return x; }")
+      deep = Enum.reduce(1..10_000, [], fn _, acc -> [acc] end)
+      assert {:error, _} = JSEngine.call("echo", [deep])
+    end
+
+    test "Maps come back as maps with keys of any type" do
+      assert {:ok, %{1 => "one", "two" => 2}} = JSEngine.run("new Map([[1, 'one'], ['two', 2]])")
+    end
+
+    test "terms with no JavaScript counterpart are rejected" do
+      assert {:ok, nil} = JSEngine.run("function echo(x) { return x; }")
+      assert {:error, "Cannot convert " <> _} = JSEngine.call("echo", [self()])
+    end
+
     test "handles objects with various value types" do
       code = """
       function complexObject() {
@@ -371,6 +1027,116 @@ defmodule JSEngineTest do
     end
   end
 
+  describe "bindings" do
+    test "bindings are visible as local variables" do
+      assert {:ok, 6} = JSEngine.run("price * qty", bindings: %{"price" => 3, "qty" => 2})
+      assert {:ok, "hi ann"} = JSEngine.run("`hi ${user.name}`", bindings: %{user: %{name: "ann"}})
+    end
+
+    test "bindings do not leak into globals" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:ok, 10} = JSEngine.run(env, "var doubled = secret * 2; doubled", bindings: %{"secret" => 5})
+      assert {:ok, "undefined"} = JSEngine.run(env, "typeof secret")
+      assert {:ok, "undefined"} = JSEngine.run(env, "typeof doubled")
+    end
+
+    test "invalid binding names are rejected" do
+      assert {:error, _} = JSEngine.run("1", bindings: %{"a){throw 1}(" => 1})
+      assert {:error, _} = JSEngine.run("1", bindings: %{"eval" => 1})
+    end
+
+    test "errors in the code are reported" do
+      assert {:error, error} = JSEngine.run("missing + 1", bindings: %{"x" => 1})
+      assert error =~ "missing is not defined"
+    end
+  end
+
+  describe "construct/4" do
+    setup do
+      {:ok, env} = JSEngine.create_env()
+
+      {:ok, nil} =
+        JSEngine.run(env, """
+        class Parser {
+          constructor(separator) { this.separator = separator; }
+          parse(text) { return text.split(this.separator); }
+        }
+        globalThis.Parser = Parser;
+        globalThis.Lib = { shapes: { Point: class { constructor(x, y) { this.x = x; this.y = y; } } } };
+        """)
+
+      {:ok, env: env}
+    end
+
+    test "classes can be instantiated with new", %{env: env} do
+      assert {:ok, %{"separator" => ","}} = JSEngine.construct(env, "Parser", [","])
+    end
+
+    test "instances can be kept as handles", %{env: env} do
+      assert {:ok, %JSEngine.Handle{} = parser} =
+               JSEngine.construct(env, "Parser", [";"], return: :handle)
+
+      assert {:ok, ["a", "b"]} = JSEngine.invoke_method(parser, "parse", ["a;b"])
+    end
+
+    test "classes can be found by dotted path", %{env: env} do
+      assert {:ok, %{"x" => 1, "y" => 2}} = JSEngine.construct(env, "Lib.shapes.Point", [1, 2])
+    end
+
+    test "non-constructors are rejected", %{env: env} do
+      assert {:error, _} = JSEngine.construct(env, "Missing", [])
+      assert {:error, _} = JSEngine.construct(env, "Math.PI", [])
+    end
+  end
+
+  describe "global variables" do
+    test "globals can be written and read without evaluating code" do
+      assert {:ok, env} = JSEngine.create_env()
+      config = %{"endpoint" => "https://example.com", "retries" => 3}
+      assert {:ok, nil} = JSEngine.set_global(env, "config", config)
+      assert {:ok, ^config} = JSEngine.get_global(env, "config")
+      assert {:ok, 3} = JSEngine.run(env, "config.retries")
+    end
+
+    test "values are stored as data, not code" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:ok, nil} = JSEngine.set_global(env, "name", "'); throw new Error('injected'); ('")
+      assert {:ok, "'); throw new Error('injected'); ('"} = JSEngine.run(env, "name")
+    end
+
+    test "nested properties can be read and written by path" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:ok, nil} = JSEngine.set_global(env, "settings", %{"theme" => "light"})
+      assert {:ok, nil} = JSEngine.set_global(env, "settings.theme", "dark")
+      assert {:ok, "dark"} = JSEngine.get_global(env, "settings.theme")
+    end
+
+    test "missing globals read as nil" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:ok, nil} = JSEngine.get_global(env, "nothingHere")
+    end
+  end
+
+  describe "call_many/3" do
+    test "makes a batch of calls with a result for each" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:ok, nil} = JSEngine.run(env, "function isEven(n) { return n % 2 === 0; }")
+
+      calls = Enum.map(1..100, &{"isEven", [&1]})
+      assert {:ok, results} = JSEngine.call_many(env, calls)
+      assert length(results) == 100
+      assert [{:ok, false}, {:ok, true} | _] = results
+    end
+
+    test "failing calls do not stop the rest" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:ok, nil} = JSEngine.run(env, "async function double(x) { return x * 2; }")
+
+      assert {:ok, [{:ok, 2}, {:error, _}, {:ok, 6}]} =
+               JSEngine.call_many(env, [{"double", [1]}, {"missing", []}, {"double", [3]}])
+    end
+  end
+
   describe "module loading" do
     test "load() supports ES modules with imports" do
       calculator_path = Path.expand("test/fixtures/modules/calculator.js")
@@ -379,6 +1145,39 @@ defmodule JSEngineTest do
       assert {:ok, 8} = JSEngine.call("calculate", [5, 3, "add"])
       assert {:ok, 15} = JSEngine.call("calculate", [5, 3, "multiply"])
     end
+
+    test "call_export/3 calls exports of ES modules with imports" do
+      scaler_path = Path.expand("test/fixtures/modules/scaler.js")
+
+      assert {:ok, nil} = JSEngine.load([scaler_path])
+      assert {:ok, [2, 4, 6]} = JSEngine.call_export(scaler_path, "scale", [[1, 2, 3], 2])
+    end
+
+    test "call_export/4 accepts file URLs and keeps exports off the global object" do
+      scaler_path = Path.expand("test/fixtures/modules/scaler.js")
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:ok, nil} = JSEngine.load(env, [scaler_path])
+
+      assert {:ok, [3, 6]} =
+               JSEngine.call_export(env, "file://" <> scaler_path, "scale", [[1, 2], 3])
+
+      assert {:ok, "undefined"} = JSEngine.run(env, "typeof scale")
+    end
+
+    test "several modules can be loaded into one environment" do
+      math_path = Path.expand("test/fixtures/modules/math.js")
+      calculator_path = Path.expand("test/fixtures/modules/calculator.js")
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:ok, nil} = JSEngine.load(env, [math_path])
+      assert {:ok, nil} = JSEngine.load(env, [calculator_path])
+      assert {:ok, 6} = JSEngine.call_export(env, math_path, "multiply", [2, 3])
+      assert {:ok, 5} = JSEngine.call_export(env, calculator_path, "calculate", [2, 3, "add"])
+    end
+
+    test "call_export/4 reports modules that were not loaded" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert {:error, _} = JSEngine.call_export(env, "/nonexistent/module.js", "fn", [])
+    end
   end
 
   describe "TypeScript support" do
@@ -401,8 +1200,8 @@ defmodule JSEngineTest do
       globalThis.multiply = multiply;
       """
 
-      # Function assignment returns the function (serialized as empty map)
-      assert {:ok, %{}} = JSEngine.run(ts_code)
+      # Function assignment returns the function as a handle
+      assert {:ok, %JSEngine.Function{}} = JSEngine.run(ts_code)
       assert {:ok, 42} = JSEngine.call("multiply", [6, 7])
     end
   end
@@ -417,9 +1216,12 @@ defmodule JSEngineTest do
       assert {:ok, "env1"} = JSEngine.run(env1, "globalThis.value = 'env1';")
       assert {:ok, "env2"} = JSEngine.run(env2, "globalThis.value = 'env2';")
 
-      # Verify isolation (function assignment returns the function as empty map)
-      assert {:ok, %{}} = JSEngine.run(env1, "globalThis.getValue = () => globalThis.value;")
-      assert {:ok, %{}} = JSEngine.run(env2, "globalThis.getValue = () => globalThis.value;")
+      # Verify isolation (function assignment returns the function as a handle)
+      assert {:ok, %JSEngine.Function{}} =
+               JSEngine.run(env1, "globalThis.getValue = () => globalThis.value;")
+
+      assert {:ok, %JSEngine.Function{}} =
+               JSEngine.run(env2, "globalThis.getValue = () => globalThis.value;")
 
       assert {:ok, "env1"} = JSEngine.call(env1, "getValue", [])
       assert {:ok, "env2"} = JSEngine.call(env2, "getValue", [])
@@ -443,10 +1245,34 @@ defmodule JSEngineTest do
       assert {:ok, "custom"} = JSEngine.run(env, "globalThis.defaultTest = 'custom';")
 
       # Default environment should still have original value
-      assert {:ok, %{}} =
+      assert {:ok, %JSEngine.Function{}} =
                JSEngine.run("globalThis.getDefault = () => globalThis.defaultTest;")
 
       assert {:ok, "default"} = JSEngine.call("getDefault", [])
     end
+
+    test "environment handles are opaque references" do
+      assert {:ok, env} = JSEngine.create_env()
+      assert is_reference(env)
+
+      # Bare ids can't be used to reach an environment
+      assert :invalid_env_id = JSEngine.run(1, "1")
+    end
+
+    test "a slow environment does not block other environments" do
+      assert {:ok, slow} = JSEngine.create_env()
+      assert {:ok, fast} = JSEngine.create_env()
+
+      slow_call =
+        Task.async(fn ->
+          JSEngine.run(slow, "new Promise(resolve => setTimeout(() => resolve('slow'), 1000))")
+        end)
+
+      {micros, result} = :timer.tc(fn -> JSEngine.run(fast, "'fast'") end)
+      assert {:ok, "fast"} = result
+      assert micros < 500_000
+
+      assert {:ok, "slow"} = Task.await(slow_call)
+    end
   end
 end